@@ -0,0 +1,130 @@
+//! On-disk cache of the parsed [`Database`], so the data stage only runs when the game data changes
+
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use fehler::throws;
+use fnv::FnvHasher;
+
+use crate::error::Error;
+use crate::recipe::{self, Database};
+
+/// Bump whenever the serialized [`Database`] changes shape
+const FORMAT: u32 = 10;
+
+/// Like [`recipe::extract_database`], but reuse the database in `cache_dir` as long as none of the
+/// source files changed, and store it there otherwise. Entries of other sources stay around.
+#[throws]
+pub fn extract_database(cache_dir: &Path, path: impl AsRef<Path>, mods: &[PathBuf]) -> Database {
+    let path = path.as_ref();
+    let file = cache_dir.join(format!("database-{:016x}.bin", key(path, mods)?));
+    if let Some(db) = load(&file) {
+        return db;
+    }
+    let db = recipe::extract_database(path, mods)?;
+    store(&file, &db)?;
+    db
+}
+
+/// Hash of the contents of everything the data stage could read. The database has both
+/// difficulties, so they share one entry.
+#[throws]
+pub fn key(path: &Path, mods: &[PathBuf]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write_u32(FORMAT);
+    hasher.write(env!("CARGO_PKG_VERSION").as_bytes());
+    for root in std::iter::once(path).chain(mods.iter().map(PathBuf::as_path)) {
+        for file in source_files(root)? {
+            let contents = fs::read(&file)?;
+            hasher.write(file.to_string_lossy().as_bytes());
+            hasher.write_usize(contents.len());
+            hasher.write(&contents);
+        }
+    }
+    hasher.finish()
+}
+
+/// `path` itself if it's a file (like a data-raw dump), else the Lua and JSON files below it,
+/// skipping the graphics and sounds
+#[throws]
+fn source_files(path: &Path) -> Vec<PathBuf> {
+    if path.is_file() {
+        return vec![path.to_owned()];
+    }
+    let mut files = Vec::new();
+    let mut dirs = vec![path.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let p = entry?.path();
+            if p.is_dir() {
+                dirs.push(p);
+            } else if p.extension().is_some_and(|e| e == "lua" || e == "json") {
+                files.push(p);
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+/// `None` if there's no entry or it's unreadable, e.g. written by another version
+fn load(file: &Path) -> Option<Database> {
+    let reader = BufReader::new(File::open(file).ok()?);
+    bincode::deserialize_from(reader).ok()
+}
+
+#[throws]
+fn store(file: &Path, db: &Database) {
+    let cache_error = |e: &dyn std::fmt::Display| Error::Cache(format!("{}: {}", file.display(), e));
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir).map_err(|e| cache_error(&e))?;
+    }
+    // concurrent runs only ever see complete entries
+    let partial = file.with_extension(format!("{}.tmp", std::process::id()));
+    let writer = BufWriter::new(File::create(&partial).map_err(|e| cache_error(&e))?);
+    bincode::serialize_into(writer, db).map_err(|e| cache_error(&e))?;
+    fs::rename(&partial, file).map_err(|e| cache_error(&e))?;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Rational;
+    use crate::recipe::Difficulty;
+
+    #[test]
+    fn invalidation() {
+        let dir = std::env::temp_dir().join(format!("factorio-ic-cache-{}", std::process::id()));
+        let dump = dir.join("data-raw-dump.json");
+        let cache_dir = dir.join("cache");
+        fs::create_dir_all(&dir).unwrap();
+        let write_dump = |result: &str| fs::write(&dump, format!(r#"{{
+            "recipe": {{ "{0}": {{
+                "name": "{0}",
+                "normal": {{ "ingredients": [["iron-plate", 2]], "result": "{0}" }},
+                "expensive": {{ "ingredients": [["iron-plate", 4]], "result": "{0}" }}
+            }} }}
+        }}"#, result)).unwrap();
+
+        write_dump("iron-gear-wheel");
+        let db = extract_database(&cache_dir, &dump, &[]).unwrap();
+        let key_before = key(&dump, &[]).unwrap();
+        assert!(cache_dir.join(format!("database-{:016x}.bin", key_before)).is_file());
+        let cached = load(&cache_dir.join(format!("database-{:016x}.bin", key_before))).unwrap();
+        assert_eq!(cached.recipes[0].name, db.recipes[0].name);
+        // switching difficulty needs no new entry
+        let mut expensive = extract_database(&cache_dir, &dump, &[]).unwrap();
+        expensive.select(Difficulty::Expensive).unwrap();
+        assert_eq!(expensive.recipes[0].ingredients[0].amount, Rational::from(4));
+        assert_eq!(fs::read_dir(&cache_dir).unwrap().count(), 1);
+
+        write_dump("iron-stick");
+        assert_ne!(key(&dump, &[]).unwrap(), key_before);
+        let db = extract_database(&cache_dir, &dump, &[]).unwrap();
+        assert_eq!(db.recipes[0].name, "iron-stick");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
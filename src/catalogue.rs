@@ -0,0 +1,176 @@
+//! Queries on the loaded recipes, to see what the solver has to work with
+
+use std::fmt;
+
+use fehler::throws;
+use fnv::FnvHashMap;
+
+use crate::Rational;
+use crate::error::Error;
+use crate::recipe::{ItemSpec, Recipe};
+
+/// Recipes indexed by what they make and take
+#[derive(Debug, Clone)]
+pub struct Catalogue<'a> {
+    recipes: &'a [Recipe],
+    producers: FnvHashMap<&'a str, Vec<&'a Recipe>>,
+    consumers: FnvHashMap<&'a str, Vec<&'a Recipe>>,
+}
+
+/// An item, the recipe we'd make it with and what that takes in turn
+#[derive(Debug, Clone)]
+pub struct IngredientTree<'a> {
+    pub item: String,
+    /// Per craft of the parent
+    pub amount: Rational,
+    /// `None` if nothing makes it, or if that would go round in circles
+    pub recipe: Option<&'a Recipe>,
+    pub cycle: bool,
+    pub ingredients: Vec<IngredientTree<'a>>,
+}
+
+impl<'a> Catalogue<'a> {
+    pub fn new(recipes: &'a [Recipe]) -> Self {
+        let mut producers: FnvHashMap<_, Vec<_>> = FnvHashMap::default();
+        let mut consumers: FnvHashMap<_, Vec<_>> = FnvHashMap::default();
+        for recipe in recipes {
+            for result in &recipe.results {
+                producers.entry(result.name.as_str()).or_default().push(recipe);
+            }
+            for ingredient in &recipe.ingredients {
+                consumers.entry(ingredient.name.as_str()).or_default().push(recipe);
+            }
+        }
+        Catalogue { recipes, producers, consumers }
+    }
+
+    pub fn recipe(&self, name: &str) -> Option<&'a Recipe> {
+        self.recipes.iter().find(|r| r.name == name)
+    }
+
+    /// Crafting categories, sorted
+    pub fn categories(&self) -> Vec<&'a str> {
+        let mut categories: Vec<_> = self.recipes.iter().map(|r| r.category.as_str()).collect();
+        categories.sort_unstable();
+        categories.dedup();
+        categories
+    }
+
+    pub fn in_category(&self, category: &str) -> Vec<&'a Recipe> {
+        self.recipes.iter().filter(|r| r.category == category).collect()
+    }
+
+    pub fn producers(&self, item: &str) -> &[&'a Recipe] {
+        self.producers.get(item).map_or(&[][..], Vec::as_slice)
+    }
+
+    pub fn consumers(&self, item: &str) -> &[&'a Recipe] {
+        self.consumers.get(item).map_or(&[][..], Vec::as_slice)
+    }
+
+    /// Ingredients that no recipe makes, sorted. The solver takes these as raw inputs, along with
+    /// those whose recipes no machine crafts (see [`Solution::unmapped_categories`]).
+    ///
+    /// [`Solution::unmapped_categories`]: crate::kirkmcdonald::Solution::unmapped_categories
+    pub fn unproduced(&self) -> Vec<&'a str> {
+        let mut items: Vec<_> = self.consumers.keys().copied().filter(|i| !self.producers.contains_key(i)).collect();
+        items.sort_unstable();
+        items
+    }
+
+    /// The recipe named like the item, else the first one that makes it
+    fn preferred_producer(&self, item: &str) -> Option<&'a Recipe> {
+        let producers = self.producers(item);
+        producers.iter().find(|r| r.name == item).or_else(|| producers.first()).copied()
+    }
+
+    /// What one craft of `recipe` takes, down to raw inputs
+    #[throws]
+    pub fn ingredient_tree(&self, recipe: &str) -> IngredientTree<'a> {
+        let recipe = self.recipe(recipe).ok_or_else(|| Error::MissingPrototype(recipe.to_owned()))?;
+        let mut path = vec![recipe.name.as_str()];
+        let ingredients = self.subtrees(&recipe.ingredients, &mut path);
+        IngredientTree { item: recipe.name.clone(), amount: Rational::from(1), recipe: Some(recipe), cycle: false, ingredients }
+    }
+
+    fn subtrees(&self, ingredients: &ItemSpec, path: &mut Vec<&'a str>) -> Vec<IngredientTree<'a>> {
+        ingredients.iter().map(|ingredient| {
+            let producer = self.preferred_producer(&ingredient.name);
+            let cycle = producer.is_some_and(|r| path.contains(&r.name.as_str()));
+            let recipe = producer.filter(|_| !cycle);
+            let ingredients = match recipe {
+                Some(recipe) => {
+                    path.push(&recipe.name);
+                    let subtrees = self.subtrees(&recipe.ingredients, path);
+                    path.pop();
+                    subtrees
+                }
+                None => vec![],
+            };
+            IngredientTree { item: ingredient.name.clone(), amount: ingredient.amount, recipe, cycle, ingredients }
+        }).collect()
+    }
+}
+
+/// `iron-gear-wheel (crafting, 0.5s): 2 iron-plate -> 1 iron-gear-wheel`
+pub fn describe(recipe: &Recipe) -> String {
+    let spec = |spec: &ItemSpec| spec.iter().map(|i| format!("{} {}", i.amount, i.name)).collect::<Vec<_>>().join(" + ");
+    format!("{} ({}, {}s): {} -> {}", recipe.name, recipe.category, recipe.crafting_time, spec(&recipe.ingredients), spec(&recipe.results))
+}
+
+impl IngredientTree<'_> {
+    fn fmt_indented(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        write!(f, "{:indent$}{} {}", "", self.amount, self.item, indent = depth * 2)?;
+        match self.recipe {
+            Some(recipe) if recipe.name != self.item => writeln!(f, " [{}]", recipe.name)?,
+            Some(_) => writeln!(f)?,
+            None if self.cycle => writeln!(f, " (cycle)")?,
+            None => writeln!(f, " (raw)")?,
+        }
+        for ingredient in &self.ingredients {
+            ingredient.fmt_indented(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for IngredientTree<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::recipe::test_util::{item, recipe};
+
+    #[test]
+    fn queries() {
+        let recipes = vec![
+            recipe(vec![item("copper-plate", 1)], vec![item("copper-cable", 2)]),
+            Recipe { category: "smelting".to_owned(), ..recipe(vec![item("copper-ore", 1)], vec![item("copper-plate", 1)]) },
+            recipe(vec![item("iron-plate", 1), item("copper-cable", 3)], vec![item("electronic-circuit", 1)]),
+            Recipe {
+                name: "kovarex-enrichment-process".to_owned(),
+                category: "centrifuging".to_owned(),
+                ..recipe(vec![item("uranium-235", 40), item("uranium-238", 5)], vec![item("uranium-235", 41), item("uranium-238", 2)])
+            },
+        ];
+        let catalogue = Catalogue::new(&recipes);
+        assert_eq!(catalogue.categories(), ["centrifuging", "crafting", "smelting"]);
+        assert_eq!(catalogue.in_category("crafting").len(), 2);
+        assert_eq!(catalogue.producers("copper-cable")[0].name, "copper-cable");
+        assert_eq!(catalogue.consumers("copper-cable")[0].name, "electronic-circuit");
+        assert!(catalogue.consumers("electronic-circuit").is_empty());
+        assert_eq!(catalogue.unproduced(), ["copper-ore", "iron-plate"]);
+
+        let tree = catalogue.ingredient_tree("electronic-circuit").unwrap();
+        assert_eq!(tree.to_string(), "1 electronic-circuit\n  1 iron-plate (raw)\n  3 copper-cable\n    1 copper-plate\n      1 copper-ore (raw)\n");
+        let tree = catalogue.ingredient_tree("kovarex-enrichment-process").unwrap();
+        assert!(tree.ingredients.iter().all(|i| i.cycle && i.recipe.is_none()));
+        assert!(catalogue.ingredient_tree("rocket-part").is_err());
+
+        assert_eq!(describe(&recipes[0]), "copper-cable (crafting, 0.5s): 1 copper-plate -> 2 copper-cable");
+    }
+}
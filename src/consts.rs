@@ -1,69 +1,391 @@
+use std::sync::Arc;
+
+use fehler::{throw, throws};
+use fnv::FnvHashMap;
+
 use crate::Rational;
+use crate::error::Error;
+use crate::pcb::InserterKind;
+use crate::recipe::{Machine, Module, Prototypes, Technology};
 
+#[derive(Debug, Clone)]
 pub struct Constants {
-    pub inserter_capacity_bonus: i32,
-    pub max_belts: BeltType, // currently we don't understand faster belts
+    // extra items per swing from research
+    pub inserter_stack_size_bonus: i32,
+    pub stack_inserter_capacity_bonus: i32,
+    // we don't build drills, but whoever mines our inputs gets this much more ore
+    pub mining_productivity: Rational,
+    pub max_belts: BeltType, // every belt we lay is of this tier
+    // preferred for everything it can craft
+    pub assembler: AssemblerTier,
+    // machine to use for a crafting category, instead of the best one that can craft it
+    pub machines: FnvHashMap<String, String>,
+    // productivity modules only go into machines whose recipe allows them
+    pub modules: Vec<String>,
+    // beacons reaching every crafting machine
+    pub beacons: usize,
+    pub beacon_modules: Vec<String>,
+    // entities the beacons, electric poles and pipes are built as
+    pub beacon: String,
+    pub pole: String,
+    pub pipe: String,
+    // supplied from outside even though we know how to make them, like intermediates off a main bus
+    pub external: Vec<String>,
+    // what the solver minimizes when several recipes could make something
+    pub objective: Objective,
+    // recipe to make an item with, no matter what the objective prefers
+    pub pinned: FnvHashMap<String, String>,
 }
 impl Default for Constants {
     fn default() -> Self {
         Self {
-            inserter_capacity_bonus: 7,
+            // all of inserter capacity bonus researched
+            inserter_stack_size_bonus: 2,
+            stack_inserter_capacity_bonus: 11,
+            mining_productivity: Rational::from(0),
             max_belts: BeltType::Normal,
+            assembler: AssemblerTier::Two,
+            machines: FnvHashMap::default(),
+            modules: vec![],
+            beacons: 0,
+            beacon_modules: vec![],
+            beacon: "beacon".to_owned(),
+            pole: "medium-electric-pole".to_owned(),
+            pipe: "pipe".to_owned(),
+            external: vec![],
+            objective: Objective::default(),
+            pinned: FnvHashMap::default(),
         }
     }
 }
 impl Constants {
-    pub fn basic_inserter_items_per_second(&self) -> Rational {
-        // TODO: understand faster belts
-        match self.inserter_capacity_bonus {
-            0 => Rational::new(94, 100),
-            2 => Rational::new(167, 100),
-            7 => Rational::new(250, 100),
-            _ => todo!(),
+    /// The machine we build for recipes of `category`: the configured one, our assembler tier,
+    /// or else the fastest electric machine that can craft it. `None` if nothing can.
+    #[throws]
+    pub fn machine_for<'a>(&self, prototypes: &'a Prototypes, category: &str) -> Option<&'a Arc<Machine>> {
+        if let Some(name) = self.machines.get(category) {
+            let machine = prototypes.machine(name)?;
+            if !machine.categories.iter().any(|c| c == category) {
+                throw!(Error::Prototype(format!("{} can't craft {} recipes", name, category)));
+            }
+            return Some(machine);
         }
+        let mut candidates: Vec<_> = prototypes.machines_for(category).collect();
+        if let Some(assembler) = candidates.iter().find(|m| m.name == self.assembler.name()) {
+            return Some(*assembler);
+        }
+        // ties go to the first name (max_by_key picks the last one), not to whatever the hash map
+        // iterates first
+        candidates.sort_by(|a, b| b.name.cmp(&a.name));
+        candidates.into_iter().max_by_key(|m| (m.electric, m.crafting_speed, m.module_slots))
+    }
+
+    /// The modules that go into `machine` for a recipe
+    #[throws]
+    pub fn machine_modules(&self, prototypes: &Prototypes, machine: &Machine, allow_productivity: bool) -> Vec<Arc<Module>> {
+        let modules = self.modules.iter().map(|name| prototypes.module(name).cloned()).collect::<Result<Vec<_>, _>>()?;
+        modules.into_iter()
+            .filter(|m| allow_productivity || m.productivity == Rational::from(0))
+            .take(machine.module_slots)
+            .collect()
+    }
+
+    /// The modules in every beacon: beacons have two slots and only take modules without productivity
+    #[throws]
+    pub fn modules_in_beacons(&self, prototypes: &Prototypes) -> Vec<Arc<Module>> {
+        let modules = self.beacon_modules.iter().map(|name| prototypes.module(name).cloned()).collect::<Result<Vec<_>, _>>()?;
+        modules.into_iter()
+            .filter(|m| m.productivity == Rational::from(0))
+            .take(2)
+            .collect()
+    }
+
+    /// What our beacons add to an effect of every machine
+    #[throws]
+    fn beacon_bonus(&self, prototypes: &Prototypes, effect: fn(&Module) -> Rational) -> Rational {
+        // beacons transmit half the effect of their modules
+        let per_beacon: Rational = self.modules_in_beacons(prototypes)?.iter().map(|m| effect(m)).sum();
+        per_beacon * Rational::new(self.beacons as i32, 2)
+    }
+
+    /// Crafting speed of `machine` with `modules` and our beacons
+    #[throws]
+    pub fn crafting_speed(&self, prototypes: &Prototypes, machine: &Machine, modules: &[Arc<Module>]) -> Rational {
+        let base = machine.crafting_speed;
+        let bonus = modules.iter().map(|m| m.speed).sum::<Rational>() + self.beacon_bonus(prototypes, |m| m.speed)?;
+        // the game never goes below 20%
+        base * std::cmp::max(Rational::from(1) + bonus, Rational::new(1, 5))
+    }
+
+    /// Factor on the energy usage of a machine with `modules` and our beacons
+    #[throws]
+    fn consumption(&self, prototypes: &Prototypes, modules: &[Arc<Module>]) -> Rational {
+        let bonus = modules.iter().map(|m| m.consumption).sum::<Rational>() + self.beacon_bonus(prototypes, |m| m.consumption)?;
+        // same floor as for speed
+        std::cmp::max(Rational::from(1) + bonus, Rational::new(1, 5))
+    }
+
+    /// kW `machine` draws while crafting with `modules` and our beacons, without the drain
+    #[throws]
+    pub fn machine_power(&self, prototypes: &Prototypes, machine: &Machine, modules: &[Arc<Module>]) -> Rational {
+        machine.energy_usage * self.consumption(prototypes, modules)?
     }
-    pub fn long_inserter_items_per_second(&self) -> Rational {
-        // TODO: understand faster belts
-        match self.inserter_capacity_bonus {
-            0 => Rational::new(118, 100),
-            2 => Rational::new(220, 100),
-            7 => Rational::new(321, 100),
-            _ => todo!(),
+
+    /// Pollution per minute of `machine` crafting with `modules` and our beacons
+    #[throws]
+    pub fn machine_pollution(&self, prototypes: &Prototypes, machine: &Machine, modules: &[Arc<Module>]) -> Rational {
+        let bonus = modules.iter().map(|m| m.pollution).sum::<Rational>();
+        machine.pollution * self.consumption(prototypes, modules)? * std::cmp::max(Rational::from(1) + bonus, Rational::new(1, 5))
+    }
+
+    /// Set the inserter bonuses of vanilla inserter capacity bonus 0-7, with stack inserters
+    /// researched
+    pub fn set_inserter_capacity_level(&mut self, level: i32) {
+        const BONUS: [i32; 8] = [0, 0, 1, 1, 1, 1, 1, 2];
+        // the stack inserter technology itself gives the first one
+        const STACK_BONUS: [i32; 8] = [1, 2, 3, 4, 5, 7, 9, 11];
+        let level = level.clamp(0, 7) as usize;
+        self.inserter_stack_size_bonus = BONUS[level];
+        self.stack_inserter_capacity_bonus = STACK_BONUS[level];
+    }
+
+    /// Replace the research bonuses with those of `technologies`
+    pub fn apply_research(&mut self, technologies: &[Technology]) {
+        self.inserter_stack_size_bonus = technologies.iter().map(|t| t.inserter_stack_size_bonus).sum();
+        self.stack_inserter_capacity_bonus = technologies.iter().map(|t| t.stack_inserter_capacity_bonus).sum();
+        self.mining_productivity = technologies.iter().map(|t| t.mining_productivity).sum();
+    }
+
+    /// Items an inserter holds per swing
+    pub fn inserter_hand_size(&self, stack: bool) -> i32 {
+        if stack { 1 + self.stack_inserter_capacity_bonus } else { 1 + self.inserter_stack_size_bonus }
+    }
+
+    /// Sustained throughput of one inserter moving items from `from` to `to`
+    #[throws]
+    pub fn inserter_items_per_second(&self, prototypes: &Prototypes, kind: InserterKind, from: InserterEnd, to: InserterEnd) -> Rational {
+        let inserter = prototypes.inserter(kind)?;
+        let hand = Rational::from(self.inserter_hand_size(inserter.stack));
+        // a full swing there and back
+        let swing = Rational::from(1) / inserter.rotation_speed / 60;
+        // containers hand over a full stack at once. A compressed lane has 4 items within reach
+        // and then we wait for every further one, when dropping we wait for room after every item.
+        let on_belt = |belt: BeltType, ready: i32| -> Result<Rational, Error> {
+            Ok(std::cmp::max(hand - ready, Rational::from(0)) / prototypes.belt(belt)?.lane_items_per_second)
+        };
+        let pickup = match from {
+            InserterEnd::Belt(belt) => on_belt(belt, 4)?,
+            InserterEnd::Chest | InserterEnd::Machine => Rational::from(0),
+        };
+        let dropoff = match to {
+            InserterEnd::Belt(belt) => on_belt(belt, 1)?,
+            InserterEnd::Chest | InserterEnd::Machine => Rational::from(0),
+        };
+        hand / (swing + pickup + dropoff)
+    }
+}
+
+/// What the solver minimizes, the others only break ties
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Objective {
+    Buildings,
+    #[default]
+    RawResources,
+    Power,
+}
+impl Objective {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "buildings" => Some(Objective::Buildings),
+            "raw-resources" => Some(Objective::RawResources),
+            "power" => Some(Objective::Power),
+            _ => None,
         }
     }
-    pub fn fast_inserter_items_per_second(&self) -> Rational {
-        // TODO: understand faster belts
-        match self.inserter_capacity_bonus {
-            0 => Rational::new(250, 100),
-            2 => Rational::new(450, 100),
-            7 => Rational::new(643, 100),
-            _ => todo!(),
+}
+
+/// Where an inserter picks up or drops off items
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InserterEnd {
+    Belt(BeltType),
+    Chest,
+    Machine,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssemblerTier {
+    One,
+    Two,
+    Three,
+}
+impl AssemblerTier {
+    pub fn from_level(level: u8) -> Option<Self> {
+        match level {
+            1 => Some(AssemblerTier::One),
+            2 => Some(AssemblerTier::Two),
+            3 => Some(AssemblerTier::Three),
+            _ => None,
         }
     }
-    pub fn stack_inserter_items_per_second(&self) -> Rational {
-        // TODO: understand faster belts
-        match self.inserter_capacity_bonus {
-            0 => Rational::new(450, 100),
-            2 => Rational::new(750, 100),
-            7 => Rational::new(750, 100), // this is probably wrong
-            _ => todo!(),
+    pub fn name(&self) -> &'static str {
+        match self {
+            AssemblerTier::One => "assembling-machine-1",
+            AssemblerTier::Two => "assembling-machine-2",
+            AssemblerTier::Three => "assembling-machine-3",
         }
     }
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum BeltType {
     Normal,
     Fast,
     Express,
 }
 impl BeltType {
-    pub fn lane_items_per_second(&self) -> Rational {
+    pub fn from_name(name: &str) -> Option<Self> {
+        [BeltType::Normal, BeltType::Fast, BeltType::Express].into_iter().find(|b| b.belt_name() == name)
+    }
+    /// Most tiles an underground belt pair can skip
+    pub fn underground_gap(&self) -> usize {
         match self {
-            BeltType::Normal => Rational::new(15, 2),
-            BeltType::Fast => Rational::new(30, 2),
-            BeltType::Express => Rational::new(45, 2),
+            BeltType::Normal => 4,
+            BeltType::Fast => 6,
+            BeltType::Express => 8,
         }
     }
+    pub fn belt_name(&self) -> &'static str {
+        match self {
+            BeltType::Normal => "transport-belt",
+            BeltType::Fast => "fast-transport-belt",
+            BeltType::Express => "express-transport-belt",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::data::RawData;
+    use crate::recipe::{prototypes_from_raw, MachineKind};
+    use crate::recipe::test_util::machine;
+
+    #[test]
+    fn speed_with_modules_and_beacons() {
+        let raw: RawData = serde_json::from_str(r#"{
+            "module": {
+                "speed-module-3": { "name": "speed-module-3", "effect": { "speed": { "bonus": 0.5 }, "consumption": { "bonus": 0.7 } } },
+                "productivity-module-3": { "name": "productivity-module-3",
+                    "effect": { "productivity": { "bonus": 0.1 }, "consumption": { "bonus": 0.8 }, "pollution": { "bonus": 0.1 }, "speed": { "bonus": -0.15 } } }
+            }
+        }"#).unwrap();
+        let db = prototypes_from_raw(&raw).unwrap();
+        let productivity3 = db.module("productivity-module-3").unwrap().clone();
+        let mut consts = Constants::default();
+        let assembler = Machine { crafting_speed: Rational::new(5, 4), module_slots: 4, ..machine("assembler", &[]) };
+        assert_eq!(consts.crafting_speed(&db, &assembler, &[]).unwrap(), Rational::new(5, 4));
+
+        consts.beacons = 2;
+        consts.beacon_modules = vec!["speed-module-3".to_owned(); 2];
+        consts.modules = vec!["productivity-module-3".to_owned(); 6];
+        assert_eq!(consts.machine_modules(&db, &assembler, true).unwrap().len(), 4);
+        assert_eq!(consts.machine_modules(&db, &assembler, false).unwrap().len(), 0);
+
+        // 1.25 * (1 + 2 * 2 * 0.5 / 2)
+        assert_eq!(consts.crafting_speed(&db, &assembler, &[]).unwrap(), Rational::new(5, 2));
+        // productivity modules slow things down, but never below 20%
+        consts.beacons = 0;
+        let slow = Machine { crafting_speed: Rational::from(2), module_slots: 2, ..machine("assembler", &[]) };
+        assert_eq!(consts.crafting_speed(&db, &slow, &vec![productivity3.clone(); 8]).unwrap(), Rational::new(2, 5));
+
+        // 150kW * (1 + 0.8 + 2 * 2 * 0.7 / 2)
+        consts.beacons = 2;
+        assert_eq!(consts.machine_power(&db, &assembler, &[productivity3.clone()]).unwrap(), Rational::from(480));
+        // 3/min * 3.2 * 1.1
+        assert_eq!(consts.machine_pollution(&db, &assembler, &[productivity3]).unwrap(), Rational::new(528, 50));
+
+        consts.modules = vec!["speed-module-4".to_owned()];
+        assert!(consts.machine_modules(&db, &assembler, true).is_err());
+    }
+
+    #[test]
+    fn machine_for_category() {
+        let raw: RawData = serde_json::from_str(r#"{
+            "assembling-machine": {
+                "assembling-machine-1": { "name": "assembling-machine-1", "crafting_speed": 0.5, "crafting_categories": ["crafting"],
+                    "collision_box": [[-1.2, -1.2], [1.2, 1.2]], "energy_source": { "type": "electric" } },
+                "assembling-machine-2": { "name": "assembling-machine-2", "crafting_speed": 0.75, "crafting_categories": ["crafting"],
+                    "collision_box": [[-1.2, -1.2], [1.2, 1.2]], "energy_source": { "type": "electric" } }
+            },
+            "furnace": {
+                "steel-furnace": { "name": "steel-furnace", "crafting_speed": 2, "crafting_categories": ["smelting"],
+                    "collision_box": [[-0.875, -0.875], [0.875, 0.875]], "energy_source": { "type": "burner" } },
+                "electric-furnace": { "name": "electric-furnace", "crafting_speed": 2, "crafting_categories": ["smelting"],
+                    "collision_box": [[-1.2, -1.2], [1.2, 1.2]], "energy_source": { "type": "electric" } }
+            }
+        }"#).unwrap();
+        let db = prototypes_from_raw(&raw).unwrap();
+        let mut consts = Constants::default();
+        let machine_for = |consts: &Constants, category| consts.machine_for(&db, category).unwrap().map(|m| m.name.clone());
+        assert_eq!(machine_for(&consts, "crafting").as_deref(), Some("assembling-machine-2"));
+        assert_eq!(machine_for(&consts, "smelting").as_deref(), Some("electric-furnace"));
+        assert_eq!(db.machine("steel-furnace").unwrap().kind, MachineKind::Furnace);
+        assert_eq!(machine_for(&consts, "chemistry"), None);
+
+        consts.assembler = AssemblerTier::One;
+        assert_eq!(machine_for(&consts, "crafting").as_deref(), Some("assembling-machine-1"));
+        consts.machines.insert("smelting".to_owned(), "steel-furnace".to_owned());
+        assert_eq!(machine_for(&consts, "smelting").as_deref(), Some("steel-furnace"));
+        consts.machines.insert("crafting".to_owned(), "steel-furnace".to_owned());
+        assert!(consts.machine_for(&db, "crafting").is_err());
+    }
+
+    #[test]
+    fn inserter_throughput() {
+        let raw: RawData = serde_json::from_str(r#"{
+            "inserter": {
+                "inserter": { "name": "inserter", "rotation_speed": 0.014 },
+                "fast-inserter": { "name": "fast-inserter", "rotation_speed": 0.04 },
+                "stack-inserter": { "name": "stack-inserter", "rotation_speed": 0.04, "stack": true }
+            },
+            "transport-belt": { "transport-belt": { "name": "transport-belt", "speed": 0.03125 } }
+        }"#).unwrap();
+        let db = prototypes_from_raw(&raw).unwrap();
+        let mut consts = Constants::default();
+        consts.set_inserter_capacity_level(0);
+        let belt = InserterEnd::Belt(BeltType::Normal);
+        let throughput = |consts: &Constants, kind, from, to| consts.inserter_items_per_second(&db, kind, from, to).unwrap();
+        assert_eq!(throughput(&consts, InserterKind::Normal, InserterEnd::Chest, InserterEnd::Machine), Rational::new(21, 25));
+        assert_eq!(throughput(&consts, InserterKind::Fast, belt, InserterEnd::Machine), Rational::new(12, 5));
+
+        consts.set_inserter_capacity_level(7);
+        assert_eq!(consts.inserter_hand_size(true), 12);
+        // 12 items per 5/12s swing, but waiting for 8 more items on a yellow lane
+        assert_eq!(throughput(&consts, InserterKind::Stack, belt, InserterEnd::Machine), Rational::new(720, 89));
+        assert_eq!(throughput(&consts, InserterKind::Stack, InserterEnd::Machine, belt), Rational::new(720, 113));
+        assert!(throughput(&consts, InserterKind::Stack, InserterEnd::Machine, InserterEnd::Machine) > Rational::from(27));
+        assert!(consts.inserter_items_per_second(&db, InserterKind::LongHanded, belt, InserterEnd::Machine).is_err());
+    }
+
+    #[test]
+    fn capacity_levels() {
+        let mut consts = Constants::default();
+        let hands = |consts: &mut Constants, level| {
+            consts.set_inserter_capacity_level(level);
+            (consts.inserter_hand_size(false), consts.inserter_hand_size(true))
+        };
+        // as the game shows them
+        assert_eq!(hands(&mut consts, 0), (1, 2));
+        assert_eq!(hands(&mut consts, 2), (2, 4));
+        assert_eq!(hands(&mut consts, 5), (2, 8));
+        assert_eq!(hands(&mut consts, 7), (3, 12));
+    }
+
+    #[test]
+    fn belt_tiers() {
+        let fast = BeltType::from_name("fast-transport-belt").unwrap();
+        assert_eq!(fast, BeltType::Fast);
+        assert_eq!(fast.underground_gap(), 6);
+        assert_eq!(BeltType::from_name("fast-underground-belt"), None);
+    }
 }
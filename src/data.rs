@@ -0,0 +1,541 @@
+//! Factorio's data stage: run the settings and data stages of core, base and mods in the game's
+//! load order and leave the prototypes in `data.raw`, or read them from a `--dump-data` dump
+
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use fehler::{throw, throws};
+use fnv::{FnvHashMap, FnvHashSet};
+use rlua::{Context, Lua, Table, Value};
+
+use crate::error::Error;
+
+/// `data.raw`, restricted to the prototype types we look at
+pub type RawData = serde_json::Map<String, serde_json::Value>;
+
+/// Prototype types we keep from `data.raw`
+pub const PROTOTYPE_TYPES: [&str; 24] = [
+    "recipe", "fluid",
+    "item", "tool", "module", "capsule", "ammo", "gun", "armor", "repair-tool", "item-with-entity-data", "rail-planner",
+    "assembling-machine", "furnace", "rocket-silo", "transport-belt", "underground-belt", "splitter", "inserter",
+    "beacon", "electric-pole", "pipe", "pipe-to-ground", "technology",
+];
+
+const SETTINGS_STAGES: [&str; 3] = ["settings.lua", "settings-updates.lua", "settings-final-fixes.lua"];
+const DATA_STAGES: [&str; 3] = ["data.lua", "data-updates.lua", "data-final-fixes.lua"];
+
+// registry keys
+const LOADED: &str = "factorio_ic_loaded";
+const STUBS: &str = "factorio_ic_stubs";
+
+/// What the engine provides to the data stage, as far as prototypes care. Evaluates to the
+/// modules `require` falls back to if there's no lualib around.
+const PRELUDE: &str = r#"
+data = { raw = {}, is_demo = false }
+function data.extend(self, prototypes)
+    -- both data:extend{...} and data.extend{...} are out there
+    if prototypes == nil then prototypes = self end
+    for _, p in ipairs(prototypes) do
+        if type(p.type) ~= "string" or type(p.name) ~= "string" then
+            error("prototype without type or name: " .. serpent.line(p))
+        end
+        data.raw[p.type] = data.raw[p.type] or {}
+        data.raw[p.type][p.name] = p
+    end
+end
+
+feature_flags = {}
+
+-- stdout is for the blueprint, so whatever mods print or log goes to stderr
+function print(...)
+    local parts = {}
+    for i = 1, select("#", ...) do parts[i] = tostring((select(i, ...))) end
+    io.stderr:write(table.concat(parts, "\t"), "\n")
+end
+log = print
+localised_print = print
+
+serpent = {}
+function serpent.line(x)
+    local seen = {}
+    local function dump(x)
+        if type(x) == "string" then return string.format("%q", x) end
+        if type(x) ~= "table" then return tostring(x) end
+        if seen[x] then return "nil --[[ref]]" end
+        seen[x] = true
+        local parts = {}
+        for k, v in pairs(x) do
+            table.insert(parts, "[" .. dump(k) .. "] = " .. dump(v))
+        end
+        return "{" .. table.concat(parts, ", ") .. "}"
+    end
+    return dump(x)
+end
+serpent.block = serpent.line
+function serpent.dump(x) return "do local _ = " .. serpent.line(x) .. "; return _; end" end
+
+-- only the numbers prototypes actually look at, everything else is a namespace of namespaces
+local function namespace()
+    return setmetatable({}, { __index = function(t, k)
+        local v = namespace()
+        rawset(t, k, v)
+        return v
+    end })
+end
+defines = namespace()
+defines.direction = { north = 0, northeast = 1, east = 2, southeast = 3, south = 4, southwest = 5, west = 6, northwest = 7 }
+
+function table.deepcopy(object)
+    local copies = {}
+    local function copy(o)
+        if type(o) ~= "table" then return o end
+        if copies[o] then return copies[o] end
+        local new = {}
+        copies[o] = new
+        for k, v in pairs(o) do new[copy(k)] = copy(v) end
+        return setmetatable(new, getmetatable(o))
+    end
+    return copy(object)
+end
+
+-- swallows whatever graphics helpers get called on it
+local sink = setmetatable({}, { __index = function(t) return t end, __call = function(t) return t end })
+
+local util = { table = { deepcopy = table.deepcopy } }
+util.copy = table.deepcopy
+function util.merge(tables)
+    local ret = {}
+    for _, t in ipairs(tables) do
+        for k, v in pairs(t) do
+            if type(v) == "table" and type(ret[k]) == "table" then
+                ret[k] = util.merge{ret[k], v}
+            else
+                ret[k] = table.deepcopy(v)
+            end
+        end
+    end
+    return ret
+end
+function util.by_pixel(x, y) return {x / 32, y / 32} end
+function util.by_pixel_hr(x, y) return {x / 64, y / 64} end
+function util.distance(a, b)
+    return math.sqrt(((a.x or a[1]) - (b.x or b[1])) ^ 2 + ((a.y or a[2]) - (b.y or b[2])) ^ 2)
+end
+function util.string_starts_with(s, start) return string.sub(s, 1, #start) == start end
+function util.list_to_map(list)
+    local map = {}
+    for _, v in ipairs(list) do map[v] = true end
+    return map
+end
+function util.empty_sprite()
+    return { filename = "__core__/graphics/empty.png", priority = "extra-high", width = 1, height = 1, frame_count = 1 }
+end
+setmetatable(util, { __index = function() return sink end })
+
+return { util = util, serpent = serpent }
+"#;
+
+/// Between the stages: startup settings take their defaults, and the setting prototypes go away
+const SETTINGS_TO_DATA: &str = r#"
+settings = { startup = {}, global = {}, player = {} }
+for _, ty in ipairs{"bool-setting", "int-setting", "double-setting", "string-setting", "color-setting"} do
+    for name, setting in pairs(data.raw[ty] or {}) do
+        settings[setting.setting_type == "runtime-per-user" and "player" or setting.setting_type == "runtime-global" and "global" or "startup"][name] = { value = setting.default_value }
+    end
+end
+data.raw = {}
+"#;
+
+#[derive(Debug, Clone)]
+pub struct Mod {
+    pub name: String,
+    pub version: String,
+    pub path: PathBuf,
+    /// Mods that have to be loaded first, and whether we can do without them
+    dependencies: Vec<(String, bool)>,
+    /// Just a directory of prototype files without info.json or data.lua, all of which are its
+    /// data stage
+    loose: bool,
+}
+
+impl Mod {
+    #[throws]
+    fn read(path: &Path) -> Mod {
+        let dir_name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let info_path = path.join("info.json");
+        let info: serde_json::Value = if info_path.is_file() {
+            serde_json::from_str(&fs::read_to_string(&info_path)?)
+                .map_err(|e| Error::DataStage(format!("{}: {}", info_path.display(), e)))?
+        } else {
+            serde_json::Value::Null
+        };
+
+        let name = info["name"].as_str().map(str::to_owned).unwrap_or(dir_name);
+        let version = info["version"].as_str().unwrap_or("0.0.0").to_owned();
+        let dependencies = match info["dependencies"].as_array() {
+            Some(deps) => deps.iter().filter_map(|d| d.as_str()).filter_map(parse_dependency).collect(),
+            // what the game assumes
+            None => match name.as_str() {
+                "core" => vec![],
+                "base" => vec![("core".to_owned(), false)],
+                _ => vec![("base".to_owned(), false)],
+            },
+        };
+        Mod { name, version, path: path.to_owned(), dependencies, loose: false }
+    }
+
+    fn stage_files(&self, stage: &str) -> Vec<PathBuf> {
+        if !self.loose {
+            return Some(self.path.join(stage)).filter(|p| p.is_file()).into_iter().collect();
+        }
+        if stage != "data.lua" {
+            return vec![];
+        }
+        let mut files: Vec<_> = fs::read_dir(&self.path).into_iter().flatten().flatten()
+            .map(|e| e.path())
+            .filter(|p| p.extension().map(|x| x == "lua").unwrap_or(false))
+            .collect();
+        files.sort();
+        files
+    }
+}
+
+/// `"? name >= 1.0"` to `("name", true)`, `None` if it doesn't affect the load order
+fn parse_dependency(dep: &str) -> Option<(String, bool)> {
+    let dep = dep.trim();
+    let (optional, rest) = if let Some(rest) = dep.strip_prefix("(?)") {
+        (true, rest)
+    } else if let Some(rest) = dep.strip_prefix('?') {
+        (true, rest)
+    } else if dep.starts_with('!') || dep.starts_with('~') {
+        return None;
+    } else {
+        (false, dep)
+    };
+    let name = rest.split(['<', '>', '=']).next().unwrap().trim();
+    Some((name.to_owned(), optional))
+}
+
+fn is_mod(path: &Path) -> bool {
+    ["info.json", "data.lua", "settings.lua"].iter().any(|f| path.join(f).is_file())
+}
+
+/// `path` is either a mod, a directory of mods (like the game's `data` directory), or a directory
+/// with loose prototype files. Zipped mods in it are an error, they need to be extracted.
+#[throws]
+pub fn discover(path: &Path) -> Vec<Mod> {
+    if is_mod(path) {
+        return vec![Mod::read(path)?];
+    }
+
+    let mut entries: Vec<_> = fs::read_dir(path)?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(|e| e.path());
+    let mut mods = Vec::new();
+    for entry in entries {
+        let p = entry.path();
+        if p.is_dir() && is_mod(&p) {
+            mods.push(Mod::read(&p)?);
+        } else if p.extension().map(|x| x == "zip").unwrap_or(false) {
+            throw!(Error::ZippedMod(p));
+        }
+    }
+    if mods.is_empty() {
+        mods.push(Mod { name: "base".to_owned(), version: "0.0.0".to_owned(), path: path.to_owned(), dependencies: vec![], loose: true });
+    }
+    mods
+}
+
+/// Dependencies first, otherwise alphabetically
+#[throws]
+pub fn load_order(mut mods: Vec<Mod>) -> Vec<Mod> {
+    let present: FnvHashSet<_> = mods.iter().map(|m| m.name.clone()).collect();
+    for m in &mods {
+        // the game always comes with core, so there's no need to insist on it
+        if let Some((missing, _)) = m.dependencies.iter().find(|(d, optional)| !optional && d != "core" && !present.contains(d)) {
+            throw!(Error::DataStage(format!("{} depends on {}, which isn't there", m.name, missing)));
+        }
+    }
+
+    mods.sort_by_key(|m| m.name.to_lowercase());
+    let mut loaded = FnvHashSet::default();
+    let mut order = Vec::new();
+    while !mods.is_empty() {
+        let next = mods.iter()
+            .position(|m| m.dependencies.iter().all(|(d, _)| loaded.contains(d) || !present.contains(d)))
+            .ok_or_else(|| Error::DataStage(format!("dependency cycle among {}", mods.iter().map(|m| m.name.as_str()).collect::<Vec<_>>().join(", "))))?;
+        let m = mods.remove(next);
+        loaded.insert(m.name.clone());
+        order.push(m);
+    }
+    order
+}
+
+/// Where `require` looks for things
+struct RequireState {
+    roots: FnvHashMap<String, PathBuf>,
+    lualib: Option<PathBuf>,
+    current_mod: PathBuf,
+    /// directories of the files currently executing
+    dirs: Vec<PathBuf>,
+}
+
+impl RequireState {
+    fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let name = name.strip_suffix(".lua").unwrap_or(name);
+        let relative = if name.contains('/') { name.to_owned() } else { name.replace('.', "/") };
+
+        // __base__/prototypes/recipe
+        if let Some((mod_name, rest)) = relative.strip_prefix("__").and_then(|r| r.split_once("__/")) {
+            return self.roots.get(mod_name).map(|root| root.join(format!("{}.lua", rest))).filter(|p| p.is_file());
+        }
+        // next to the current file, then from the mod root, then the game's lualib
+        self.dirs.last().into_iter()
+            .chain(Some(&self.current_mod))
+            .chain(self.lualib.as_ref())
+            .map(|dir| dir.join(format!("{}.lua", relative)))
+            .find(|p| p.is_file())
+    }
+}
+
+fn require<'lua>(ctx: Context<'lua>, state: &Mutex<RequireState>, name: &str) -> rlua::Result<Value<'lua>> {
+    let resolved = state.lock().unwrap().resolve(name);
+    let path = match resolved {
+        Some(path) => path,
+        None => {
+            let stubs: Table = ctx.named_registry_value(STUBS)?;
+            return match stubs.get::<_, Value>(name)? {
+                Value::Nil => Err(rlua::Error::RuntimeError(format!("module {} not found", name))),
+                stub => Ok(stub),
+            };
+        }
+    };
+
+    // every mod gets its own package.loaded
+    let loaded: Table = ctx.named_registry_value(LOADED)?;
+    let key = path.to_string_lossy().into_owned();
+    match loaded.get::<_, Value>(key.as_str())? {
+        Value::Nil => (),
+        module => return Ok(module),
+    }
+
+    state.lock().unwrap().dirs.push(path.parent().unwrap().to_owned());
+    let result = exec_file(ctx, &path);
+    state.lock().unwrap().dirs.pop();
+    let module = match result? {
+        Value::Nil => Value::Boolean(true),
+        module => module,
+    };
+    loaded.set(key, module.clone())?;
+    Ok(module)
+}
+
+fn exec_file<'lua>(ctx: Context<'lua>, path: &Path) -> rlua::Result<Value<'lua>> {
+    let code = fs::read_to_string(path).map_err(rlua::Error::external)?;
+    let code = code.strip_prefix('\u{feff}').unwrap_or(&code);
+    ctx.load(code).set_name(&format!("@{}", path.display()))?.call(())
+}
+
+#[throws]
+fn run_stage(ctx: Context, state: &Mutex<RequireState>, mods: &[Mod], stage: &str) {
+    for m in mods {
+        for file in m.stage_files(stage) {
+            ctx.set_named_registry_value(LOADED, ctx.create_table()?)?;
+            {
+                let mut state = state.lock().unwrap();
+                state.current_mod = m.path.clone();
+                state.dirs = vec![m.path.clone()];
+            }
+            exec_file(ctx, &file)?;
+        }
+    }
+}
+
+/// Run the settings and data stages of all mods found in `paths` (see [`discover`]).
+#[throws]
+pub fn load(paths: &[PathBuf]) -> Lua {
+    let mut mods = Vec::new();
+    for path in paths {
+        mods.extend(discover(path)?);
+    }
+    let mods = load_order(mods)?;
+
+    let state = Arc::new(Mutex::new(RequireState {
+        roots: mods.iter().map(|m| (m.name.clone(), m.path.clone())).collect(),
+        lualib: mods.iter().find(|m| m.name == "core").map(|m| m.path.join("lualib")).filter(|p| p.is_dir()),
+        current_mod: PathBuf::new(),
+        dirs: vec![],
+    }));
+
+    let lua = Lua::new();
+    lua.context(|ctx| -> Result<(), Error> {
+        let stubs: Table = ctx.load(PRELUDE).set_name("prelude")?.eval()?;
+        ctx.set_named_registry_value(STUBS, stubs)?;
+
+        let mods_table = ctx.create_table()?;
+        for m in &mods {
+            mods_table.set(m.name.as_str(), m.version.as_str())?;
+        }
+        ctx.globals().set("mods", mods_table)?;
+        let require_state = state.clone();
+        ctx.globals().set("require", ctx.create_function(move |ctx, name: String| require(ctx, &require_state, &name))?)?;
+
+        for stage in SETTINGS_STAGES {
+            run_stage(ctx, &state, &mods, stage)?;
+        }
+        ctx.load(SETTINGS_TO_DATA).set_name("settings")?.exec()?;
+        for stage in DATA_STAGES {
+            run_stage(ctx, &state, &mods, stage)?;
+        }
+        Ok(())
+    })?;
+    lua
+}
+
+/// Read the `data-raw-dump.json` written by `factorio --dump-data`.
+#[throws]
+pub fn load_dump(path: &Path) -> RawData {
+    let mut raw: RawData = serde_json::from_reader(BufReader::new(File::open(path)?))
+        .map_err(|e| Error::DataStage(format!("{}: {}", path.display(), e)))?;
+    raw.retain(|ty, _| PROTOTYPE_TYPES.contains(&ty.as_str()));
+    raw
+}
+
+/// Convert what the data stage left in `data.raw` to the same shape as [`load_dump`]
+#[throws]
+pub fn raw_data(lua: &Lua) -> RawData {
+    lua.context(|ctx| -> Result<_, Error> {
+        let data: Table = ctx.globals().get("data")?;
+        let raw: Table = data.get("raw")?;
+        let mut result = RawData::new();
+        for ty in PROTOTYPE_TYPES {
+            if let Some(prototypes) = raw.get::<_, Option<Table>>(ty)? {
+                result.insert(ty.to_owned(), to_json(Value::Table(prototypes), 0)?);
+            }
+        }
+        Ok(result)
+    })?
+}
+
+/// Tables become arrays if they're sequences, like in the game's own dump
+fn to_json(value: Value, depth: usize) -> rlua::Result<serde_json::Value> {
+    use serde_json::Value as Json;
+    Ok(match value {
+        Value::Boolean(b) => Json::Bool(b),
+        Value::Integer(i) => Json::from(i),
+        Value::Number(n) => serde_json::Number::from_f64(n).map(Json::Number).unwrap_or(Json::Null),
+        Value::String(s) => Json::String(String::from_utf8_lossy(s.as_bytes()).into_owned()),
+        // nothing in a prototype is nested this deep, but reference cycles are
+        Value::Table(_) if depth > 64 => Json::Null,
+        Value::Table(t) => {
+            let len = t.raw_len();
+            let pairs = t.pairs::<Value, Value>().collect::<rlua::Result<Vec<_>>>()?;
+            let is_sequence = len > 0 && pairs.len() as i64 == len
+                && pairs.iter().all(|(k, _)| matches!(k, Value::Integer(i) if (1..=len).contains(i)));
+            if is_sequence {
+                let mut seq = vec![Json::Null; len as usize];
+                for (k, v) in pairs {
+                    if let Value::Integer(i) = k {
+                        seq[i as usize - 1] = to_json(v, depth + 1)?;
+                    }
+                }
+                Json::Array(seq)
+            } else {
+                let mut map = serde_json::Map::new();
+                for (k, v) in pairs {
+                    let key = match k {
+                        Value::String(s) => String::from_utf8_lossy(s.as_bytes()).into_owned(),
+                        Value::Integer(i) => i.to_string(),
+                        Value::Number(n) => n.to_string(),
+                        Value::Boolean(b) => b.to_string(),
+                        _ => continue,
+                    };
+                    map.insert(key, to_json(v, depth + 1)?);
+                }
+                Json::Object(map)
+            }
+        }
+        _ => Json::Null,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Rational;
+    use crate::recipe::prototypes_from_raw;
+
+    fn m(name: &str, dependencies: &[&str]) -> Mod {
+        Mod {
+            name: name.to_owned(),
+            version: "1.0.0".to_owned(),
+            path: PathBuf::new(),
+            dependencies: dependencies.iter().filter_map(|d| parse_dependency(d)).collect(),
+            loose: false,
+        }
+    }
+
+    #[test]
+    fn mod_load_order() {
+        let mods = vec![
+            m("Alpha", &["base >= 1.1", "zeta"]),
+            m("base", &["core"]),
+            m("beta", &["? not-installed", "! alpha", "~ gamma"]),
+            m("gamma", &["(?) beta"]),
+            m("zeta", &["base"]),
+        ];
+        let order: Vec<_> = load_order(mods).unwrap().into_iter().map(|m| m.name).collect();
+        assert_eq!(order, ["base", "beta", "gamma", "zeta", "Alpha"]);
+
+        assert!(load_order(vec![m("a", &["b"])]).is_err());
+        assert!(load_order(vec![m("a", &["b"]), m("b", &["a"])]).is_err());
+    }
+
+    #[test]
+    fn zipped_mods() {
+        let dir = std::env::temp_dir().join(format!("factorio-ic-zipped-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("some-mod_1.0.0.zip"), b"").unwrap();
+
+        assert!(matches!(discover(&dir), Err(Error::ZippedMod(p)) if p == dir.join("some-mod_1.0.0.zip")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn kept_prototypes() {
+        let dir = std::env::temp_dir().join(format!("factorio-ic-dump-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dump = dir.join("data-raw-dump.json");
+        fs::write(&dump, r#"{
+            "electric-pole": { "medium-electric-pole": { "name": "medium-electric-pole", "supply_area_distance": 3.5, "maximum_wire_distance": 9 } },
+            "beacon": { "beacon": { "name": "beacon", "energy_usage": "480kW", "energy_source": { "type": "electric" } } },
+            "pipe": { "pipe": { "name": "pipe" } },
+            "pipe-to-ground": { "pipe-to-ground": { "name": "pipe-to-ground" } },
+            "corpse": { "small-remnants": { "name": "small-remnants" } }
+        }"#).unwrap();
+        let dumped = load_dump(&dump).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(!dumped.contains_key("corpse"));
+
+        let lua = Lua::new();
+        lua.context(|ctx| -> rlua::Result<()> {
+            ctx.load(PRELUDE).exec()?;
+            ctx.load(r#"data:extend{
+                { type = "electric-pole", name = "medium-electric-pole", supply_area_distance = 3.5, maximum_wire_distance = 9 },
+                { type = "beacon", name = "beacon", energy_usage = "480kW", energy_source = { type = "electric" } },
+                { type = "pipe", name = "pipe" },
+                { type = "pipe-to-ground", name = "pipe-to-ground" },
+            }"#).exec()
+        }).unwrap();
+        let staged = raw_data(&lua).unwrap();
+
+        for raw in [dumped, staged] {
+            let prototypes = prototypes_from_raw(&raw).unwrap();
+            assert_eq!(prototypes.pole("medium-electric-pole").unwrap().wire_reach, Rational::from(9));
+            assert_eq!(prototypes.beacon("beacon").unwrap().energy_usage, Rational::from(480));
+            assert_eq!(prototypes.pipe("pipe").unwrap().underground.as_deref(), Some("pipe-to-ground"));
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::pcb::Point;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading the recipe files failed
+    Io(io::Error),
+    /// Executing the recipe prototypes failed
+    Lua(rlua::Error),
+    /// The mods can't be loaded, e.g. because of missing dependencies
+    DataStage(String),
+    /// Mods packed as zip files aren't supported, they need to be extracted first
+    ZippedMod(PathBuf),
+    /// Writing the parsed recipes to the cache failed
+    Cache(String),
+    /// A prototype is missing a field or has one of the wrong type
+    Prototype(String),
+    /// The game data doesn't have an entity we need
+    MissingPrototype(String),
+    /// A recipe override doesn't fit the recipes it's applied to
+    Override(String),
+    /// The solver can't produce the requested item
+    Solver(String),
+    /// The placer can't build the production graph
+    Placement(String),
+    /// Tried to put an entity on a tile that is already occupied
+    Conflict(Point),
+    /// None of the routing workers managed to connect all wires
+    Routing,
+    /// Encoding the blueprint string failed
+    Encoding(String),
+    /// Writing a result to this file failed
+    Write(PathBuf, io::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "failed to read recipes: {}", e),
+            // rlua only mentions the cause of errors from Rust callbacks (like require) as their source
+            Error::Lua(rlua::Error::CallbackError { traceback, cause }) => write!(f, "failed to execute recipes: {}\n{}", cause, traceback),
+            Error::Lua(e) => write!(f, "failed to execute recipes: {}", e),
+            Error::DataStage(e) => write!(f, "data stage: {}", e),
+            Error::ZippedMod(p) => write!(f, "{} is a zipped mod, extract it to load it", p.display()),
+            Error::Cache(e) => write!(f, "failed to write the recipe cache: {}", e),
+            Error::Prototype(e) => write!(f, "malformed prototype: {}", e),
+            Error::MissingPrototype(p) => write!(f, "there is no prototype for {}", p),
+            Error::Override(e) => write!(f, "recipe override: {}", e),
+            Error::Solver(e) => write!(f, "solver: {}", e),
+            Error::Placement(e) => write!(f, "placement: {}", e),
+            Error::Conflict(p) => write!(f, "conflicting entities at ({}, {})", p.x, p.y),
+            Error::Routing => write!(f, "failed to route all wires"),
+            Error::Encoding(e) => write!(f, "failed to encode blueprint: {}", e),
+            Error::Write(p, e) => write!(f, "failed to write {}: {}", p.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) | Error::Write(_, e) => Some(e),
+            Error::Lua(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<rlua::Error> for Error {
+    fn from(e: rlua::Error) -> Self {
+        Error::Lua(e)
+    }
+}
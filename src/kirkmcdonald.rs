@@ -1,77 +1,560 @@
+use std::sync::Arc;
+
+use fehler::{throw, throws};
+use fnv::FnvHashMap;
+use itertools::Itertools;
+
 use crate::Rational;
+use crate::consts::{Constants, Objective};
+use crate::error::Error;
+use crate::lp;
+use crate::overrides::Compound;
 use crate::pcb::WireKind;
-use crate::recipe::{Category, Recipe, Ingredient};
+use crate::recipe::{MachineKind, Recipe, Ingredient, Machine, Module, Prototypes, Temperature};
 
-#[derive(Debug)]
+/// What to build: a node per recipe and per external input, connected by the items flowing
+/// between them. Every recipe shows up once, no matter how many others consume its results.
+#[derive(Debug, Clone, Default)]
 pub struct ProductionGraph {
+    pub nodes: Vec<ProductionNode>,
+    pub edges: Vec<ProductionEdge>,
+    /// what leaves the factory, in the order it was asked for
+    pub products: Vec<(String, Rational)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProductionNode {
+    /// `None` for external inputs, which supply `output` from outside
+    pub recipe: Option<String>,
+    /// the result we run the recipe for
     pub output: String,
     pub output_kind: WireKind,
+    /// net production of `output`
     pub per_second: Rational,
 
+    /// zero for external inputs
     pub how_many: Rational,
-    pub building: Option<Category>,
+    pub building: Option<MachineKind>,
+    pub machine: Option<Arc<Machine>>,
+    pub modules: Vec<Arc<Module>>,
+    // other results of the recipe that come out of the same building, not counting those it
+    // consumes more of than it makes
+    pub byproducts: Vec<String>,
+    pub compound: Option<Compound>,
+}
 
-    // has no input nodes if this node "produces" raw ores, i.e. is an external input
-    pub inputs: Vec<ProductionGraph>,
+/// Items flowing from one node to another, or out of the factory if `to` is `None`. A node
+/// consuming some of its own output has an edge to itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProductionEdge {
+    pub from: usize,
+    pub to: Option<usize>,
+    pub item: String,
+    pub kind: WireKind,
+    pub per_second: Rational,
 }
 
-pub fn kirkmcdonald(recipes: &[Recipe], desired: &str, desired_per_second: Rational, output_kind: &WireKind) -> ProductionGraph {
-    if output_kind != &WireKind::Belt {
-        // right now we just blindly assume that we can't produce pipe outputs ever
-        return ProductionGraph {
-            output: desired.to_owned(),
-            output_kind: output_kind.clone(),
-            per_second: desired_per_second,
+impl ProductionGraph {
+    /// Edges into `node`, in the order of its recipe's ingredients
+    pub fn inputs(&self, node: usize) -> impl Iterator<Item = &ProductionEdge> + '_ {
+        self.edges.iter().filter(move |e| e.to == Some(node))
+    }
 
-            how_many: Rational::from(-1),
-            building: None,
+    /// Edges out of `node`
+    pub fn outputs(&self, node: usize) -> impl Iterator<Item = &ProductionEdge> + '_ {
+        self.edges.iter().filter(move |e| e.from == node)
+    }
+}
 
-            inputs: vec![],
-        };
+/// Result of [`kirkmcdonald`]
+#[derive(Debug, Clone)]
+pub struct Solution {
+    pub graph: ProductionGraph,
+    /// crafts per second of every recipe the plan uses
+    pub recipes: Vec<(String, Rational)>,
+    /// external inputs per second
+    pub inputs: Vec<(String, Rational)>,
+    /// byproducts per second that nothing consumes
+    pub surplus: Vec<(String, Rational)>,
+    /// crafting categories of recipes we could have used, but no machine crafts
+    pub unmapped_categories: Vec<String>,
+}
+
+// everything below this is solver noise
+const EPSILON: f64 = 1e-9;
+// weight of what the objective isn't about, relative to what it is
+const TIE_BREAK: f64 = 1e-3;
+
+/// The machines running a recipe
+#[derive(Debug, Clone)]
+struct Setup {
+    machine: Arc<Machine>,
+    modules: Vec<Arc<Module>>,
+    speed: Rational,
+    productivity: Rational,
+}
+
+/// How we'd build `recipe`, `None` if no machine crafts its category
+#[throws]
+fn setup(consts: &Constants, prototypes: &Prototypes, recipe: &Recipe) -> Option<Setup> {
+    let machine = match consts.machine_for(prototypes, &recipe.category)? {
+        Some(machine) => machine.clone(),
+        None => return None,
+    };
+    let modules = consts.machine_modules(prototypes, &machine, recipe.allow_productivity)?;
+    let speed = consts.crafting_speed(prototypes, &machine, &modules)?;
+    let productivity = Module::total_productivity(&modules);
+    Some(Setup { machine, modules, speed, productivity })
+}
+
+fn raw_cost(kind: &WireKind) -> f64 {
+    match kind {
+        // offshore pumps are practically free
+        WireKind::Pipe(name) if name == "water" => 0.,
+        // fluids come in much larger amounts than items
+        WireKind::Pipe(_) => 0.1,
+        WireKind::Belt => 1.,
     }
+}
 
-    if let Some(recipe) = recipes.iter().filter(|x| (x.results.len() == 1) && (x.results[0].name == desired)).next() {
-        let results_per_step = recipe.results[0].amount;
-        let step_duration = Rational::approximate_float(recipe.crafting_time).unwrap();
-        let results_per_second = results_per_step / step_duration;
-        let how_many_concurrents = desired_per_second / results_per_second;
+/// Find the cheapest combination of recipes producing the `desired` items at their rates per
+/// second, all at once so that shared intermediates are made by the same recipes. What's
+/// cheapest is up to [`Constants::objective`], except for items [`Constants::pinned`] to a recipe.
+///
+/// Recipes may have several results, any of them may be picked among alternatives and every
+/// result that isn't consumed ends up in [`Solution::surplus`]. Recipes no machine can craft
+/// aren't used, so what they'd make becomes an external input, and so does everything in
+/// [`Constants::external`].
+#[throws]
+pub fn kirkmcdonald(recipes: &[Recipe], prototypes: &Prototypes, desired: &[(String, Rational)], output_kind: &WireKind, consts: &Constants) -> Solution {
+    let setups = recipes.iter().map(|r| setup(consts, prototypes, r)).collect::<Result<Vec<_>, _>>()?;
+    let mut unmapped_categories = Vec::new();
 
-        let building_base_speed = match recipe.category {
-            Category::Assembler => Rational::new(3, 4),
-            Category::Furnace => Rational::from(2),
-            _ => Rational::from(-1), // unimplemented
-        };
-        let how_many = how_many_concurrents / building_base_speed;
-
-        let inputs = recipe
-            .ingredients
-            .iter()
-            .map(|&Ingredient { ref name, amount, ref kind }| {
-                kirkmcdonald(recipes, name, amount / results_per_step * desired_per_second, kind)
-            })
-            .collect();
+    // only look at recipes that can contribute to what we want
+    let mut candidates = Vec::new();
+    let mut items: Vec<(String, WireKind)> = Vec::new();
+    let mut item_index: FnvHashMap<String, usize> = FnvHashMap::default();
+    for (name, _) in desired {
+        if item_index.contains_key(name) {
+            throw!(Error::Solver(format!("{} is asked for twice", name)));
+        }
+        if consts.external.contains(name) {
+            throw!(Error::Solver(format!("{} is asked for, but supplied from outside", name)));
+        }
+        item_index.insert(name.clone(), items.len());
+        items.push((name.clone(), output_kind.clone()));
+    }
+    // fluids the candidates so far need within some temperature range
+    let mut accepted: FnvHashMap<String, Temperature> = FnvHashMap::default();
+    let mut next = 0;
+    while next < items.len() {
+        let item = items[next].0.clone();
+        next += 1;
+        // the factory ends here, whatever the recipes behind it would need
+        if consts.external.contains(&item) {
+            continue;
+        }
+        let pinned = consts.pinned.get(&item);
+        if let Some(name) = pinned {
+            if !recipes.iter().any(|r| r.name == *name && r.results.iter().any(|x| x.name == item)) {
+                throw!(Error::Solver(format!("{} is pinned to {}, which isn't available or doesn't make it", item, name)));
+            }
+        }
+        for (r, recipe) in recipes.iter().enumerate() {
+            let makes = |x: &Ingredient| x.name == item && match (accepted.get(&item), &x.temperature) {
+                (Some(range), Some(t)) => range.contains(t),
+                _ => true,
+            };
+            if candidates.contains(&r) || !recipe.results.iter().any(makes) || pinned.is_some_and(|name| *name != recipe.name) {
+                continue;
+            }
+            if setups[r].is_none() {
+                if pinned.is_some() {
+                    throw!(Error::Solver(format!("{} is pinned to {}, but no machine crafts {}", item, recipe.name, recipe.category)));
+                }
+                if !unmapped_categories.contains(&recipe.category) {
+                    unmapped_categories.push(recipe.category.clone());
+                }
+                continue;
+            }
+            candidates.push(r);
+            for x in &recipe.ingredients {
+                if let Some(ref t) = x.temperature {
+                    let range = accepted.get(&x.name).map_or(*t, |range| range.intersect(t));
+                    accepted.insert(x.name.clone(), range);
+                }
+            }
+            for x in recipe.ingredients.iter().chain(&recipe.results) {
+                if !item_index.contains_key(&x.name) {
+                    item_index.insert(x.name.clone(), items.len());
+                    items.push((x.name.clone(), x.kind.clone()));
+                }
+            }
+        }
+    }
 
-        ProductionGraph {
-            output: desired.to_owned(),
-            output_kind: output_kind.clone(),
-            per_second: desired_per_second,
+    // variables: crafts per second of every candidate, then supply and surplus of every item
+    let nr = candidates.len();
+    let ni = items.len();
+    let mut a = vec![vec![0.; nr + 2 * ni]; ni];
+    let mut costs = vec![0.; nr + 2 * ni];
+    let mut produced = vec![false; ni];
+    let weight = |objective| if consts.objective == objective { 1. } else { TIE_BREAK };
+    for (j, &r) in candidates.iter().enumerate() {
+        let recipe = &recipes[r];
+        let Setup { machine, modules, speed, productivity } = setups[r].as_ref().unwrap();
+        for x in &recipe.ingredients {
+            a[item_index[&x.name]][j] -= lp::to_f64(x.amount);
+        }
+        for x in &recipe.results {
+            a[item_index[&x.name]][j] += lp::to_f64(x.with_productivity(*productivity));
+        }
+        for x in &recipe.results {
+            // kovarex "produces" uranium-238, but only returns some of what it consumes
+            produced[item_index[&x.name]] |= a[item_index[&x.name]][j] > 0.;
+        }
+        let buildings = recipe.crafting_time / lp::to_f64(*speed);
+        let megawatts = lp::to_f64(consts.machine_power(prototypes, machine, modules)?) / 1000.;
+        costs[j] = buildings * (weight(Objective::Buildings) + weight(Objective::Power) * megawatts);
+    }
+    for (i, (_, kind)) in items.iter().enumerate() {
+        a[i][nr + i] = 1.;
+        a[i][nr + ni + i] = -1.;
+        costs[nr + i] = weight(Objective::RawResources) * raw_cost(kind);
+    }
+    let mut b = vec![0.; ni];
+    for (name, per_second) in desired {
+        b[item_index[name]] = lp::to_f64(*per_second);
+    }
+    let no_plan = || Error::Solver(format!("can't find a production plan for {}", desired.iter().map(|(name, _)| name.as_str()).join(", ")));
 
-            how_many,
-            building: Some(recipe.category),
+    // making what we know a recipe for beats any objective: find the least of it we have to supply,
+    // then optimize the objective supplying no more than that, the rest going to a slack variable
+    let mut supplied = vec![0.; nr + 2 * ni];
+    for i in (0..ni).filter(|&i| produced[i]) {
+        supplied[nr + i] = 1.;
+    }
+    let least: f64 = match lp::minimize(&a, &b, &supplied)? {
+        Some(x) => supplied.iter().zip(&x).map(|(s, x)| s * x).sum(),
+        None => throw!(no_plan()),
+    };
+    for row in &mut a {
+        row.push(0.);
+    }
+    supplied.push(1.);
+    a.push(supplied);
+    b.push(least.max(0.) + EPSILON);
+    costs.push(0.);
+
+    let x = match lp::minimize(&a, &b, &costs)? {
+        Some(x) => x,
+        None => throw!(no_plan()),
+    };
 
-            inputs,
+    let rates: Vec<(usize, f64)> = candidates.iter().copied().zip(x.iter().copied()).filter(|&(_, x)| x > EPSILON).collect();
+    let collect_items = |offset: usize| {
+        items.iter().enumerate()
+            .filter(|&(i, _)| x[offset + i] > EPSILON)
+            .map(|(i, (name, _))| Ok((name.clone(), lp::to_rational(x[offset + i])?)))
+            .collect::<Result<Vec<_>, Error>>()
+    };
+    let inputs = collect_items(nr)?;
+    let surplus = collect_items(nr + ni)?;
+
+    let graph = production_graph(recipes, &setups, &rates, &items, &x[nr..nr + ni], desired)?;
+    for (name, _) in desired {
+        if !graph.nodes.iter().any(|n| n.recipe.is_some() && n.output == *name) {
+            throw!(Error::Solver(format!("there is no recipe for {}", name)));
         }
-    } else {
-        ProductionGraph {
-            output: desired.to_owned(),
-            output_kind: output_kind.clone(),
-            per_second: desired_per_second,
+    }
 
-            how_many: Rational::from(-1),
-            building: None,
+    Solution {
+        graph,
+        recipes: rates.iter().map(|&(r, x)| Ok((recipes[r].name.clone(), lp::to_rational(x)?))).collect::<Result<_, Error>>()?,
+        inputs,
+        surplus,
+        unmapped_categories,
+    }
+}
 
-            inputs: vec![],
+/// Turn the recipe rates and external supplies into a graph. Where several nodes make an item,
+/// every consumer gets its share from each of them.
+#[throws]
+fn production_graph(
+    recipes: &[Recipe], setups: &[Option<Setup>], rates: &[(usize, f64)], items: &[(String, WireKind)], supply: &[f64],
+    desired: &[(String, Rational)],
+) -> ProductionGraph {
+    // gross production and consumption of every recipe node
+    let mut produced: Vec<FnvHashMap<&str, Rational>> = Vec::new();
+    let mut consumed: Vec<FnvHashMap<&str, Rational>> = Vec::new();
+    let mut nodes = Vec::new();
+    for &(r, x) in rates {
+        let recipe = &recipes[r];
+        let Setup { machine, modules, speed, productivity } = setups[r].clone().unwrap();
+        let crafts = lp::to_rational(x)?;
+        let mut made = FnvHashMap::default();
+        for result in &recipe.results {
+            *made.entry(result.name.as_str()).or_insert_with(|| Rational::from(0)) += result.with_productivity(productivity) * crafts;
+        }
+        let mut used = FnvHashMap::default();
+        for ingredient in &recipe.ingredients {
+            *used.entry(ingredient.name.as_str()).or_insert_with(|| Rational::from(0)) += ingredient.amount * crafts;
+        }
+        let net = |item: &str| amount(&made, item) - amount(&used, item);
+
+        // what we asked for, else what another recipe needs, else whatever it makes
+        let needed = |item: &str| rates.iter().any(|&(o, _)| o != r && recipes[o].ingredients.iter().any(|x| x.name == item));
+        let net_results: Vec<&Ingredient> = recipe.results.iter().filter(|x| net(&x.name) > Rational::from(0)).collect();
+        let output = net_results.iter().find(|x| desired.iter().any(|(d, _)| *d == x.name))
+            .or_else(|| net_results.iter().find(|x| needed(&x.name)))
+            .or_else(|| net_results.first())
+            .ok_or_else(|| Error::Solver(format!("{} doesn't make anything", recipe.name)))?;
+
+        let crafting_time = Rational::approximate_float(recipe.crafting_time)
+            .ok_or_else(|| Error::Solver(format!("bad crafting time for {}: {}", recipe.name, recipe.crafting_time)))?;
+        nodes.push(ProductionNode {
+            recipe: Some(recipe.name.clone()),
+            output: output.name.clone(),
+            output_kind: output.kind.clone(),
+            per_second: net(&output.name),
+
+            how_many: crafts * crafting_time / speed,
+            building: Some(machine.kind),
+            machine: Some(machine),
+            modules,
+            byproducts: net_results.iter().filter(|x| x.name != output.name).map(|x| x.name.clone()).collect(),
+            compound: recipe.compound.clone(),
+        });
+        produced.push(made);
+        consumed.push(used);
+    }
+    let mut suppliers = FnvHashMap::default();
+    for ((name, kind), &x) in items.iter().zip(supply) {
+        if x > EPSILON {
+            suppliers.insert(name.as_str(), nodes.len());
+            nodes.push(ProductionNode {
+                recipe: None,
+                output: name.clone(),
+                output_kind: kind.clone(),
+                per_second: lp::to_rational(x)?,
+
+                how_many: Rational::from(0),
+                building: None,
+                machine: None,
+                modules: vec![],
+                byproducts: vec![],
+                compound: None,
+            });
         }
     }
+
+    // net amounts every node makes of an item
+    let makers = |item: &str| -> Vec<(usize, Rational)> {
+        let mut makers: Vec<_> = (0..rates.len())
+            .map(|n| (n, amount(&produced[n], item) - amount(&consumed[n], item)))
+            .filter(|&(_, x)| x > Rational::from(0))
+            .collect();
+        makers.extend(suppliers.get(item).map(|&n| (n, nodes[n].per_second)));
+        makers
+    };
+    let kind_of = |item: &str| items.iter().find(|(name, _)| name == item).map_or(WireKind::Belt, |(_, kind)| kind.clone());
+    let connect = |item: &str, to: Option<usize>, per_second: Rational| -> Vec<ProductionEdge> {
+        let makers = makers(item);
+        let total: Rational = makers.iter().map(|&(_, x)| x).sum();
+        if total == Rational::from(0) {
+            return vec![];
+        }
+        let share = |made: Rational| if made == total { per_second } else { per_second * made / total };
+        makers.into_iter()
+            .map(|(from, made)| ProductionEdge { from, to, item: item.to_owned(), kind: kind_of(item), per_second: share(made) })
+            .collect()
+    };
+    let mut edges = Vec::new();
+    for (n, &(r, _)) in rates.iter().enumerate() {
+        for item in recipes[r].ingredients.iter().map(|x| x.name.as_str()).unique() {
+            let made = amount(&produced[n], item);
+            let used = amount(&consumed[n], item);
+            if made > Rational::from(0) {
+                // fed back into itself
+                edges.push(ProductionEdge { from: n, to: Some(n), item: item.to_owned(), kind: kind_of(item), per_second: std::cmp::min(made, used) });
+            }
+            if used > made {
+                edges.extend(connect(item, Some(n), used - made));
+            }
+        }
+    }
+    for (name, per_second) in desired {
+        edges.extend(connect(name, None, *per_second));
+    }
+
+    ProductionGraph { nodes, edges, products: desired.to_vec() }
 }
 
+fn amount(amounts: &FnvHashMap<&str, Rational>, item: &str) -> Rational {
+    amounts.get(item).copied().unwrap_or_else(|| Rational::from(0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::recipe::test_util::{fluid, item, machine, recipe};
+
+    /// One machine for each of `categories`
+    fn machines(categories: &[&str]) -> Prototypes {
+        let mut prototypes = Prototypes::default();
+        for &category in categories {
+            let machine = machine(&format!("{}-machine", category), &[category]);
+            prototypes.machines.insert(machine.name.clone(), Arc::new(machine));
+        }
+        prototypes
+    }
+
+    #[test]
+    fn byproducts_and_alternatives() {
+        let oil_processing = |name: &str, ingredients, results| Recipe {
+            name: name.to_owned(),
+            category: "oil-processing".to_owned(),
+            crafting_time: 5.,
+            ..recipe(ingredients, results)
+        };
+        let recipes = vec![
+            oil_processing("basic-oil-processing", vec![fluid("crude-oil", 100)], vec![fluid("petroleum-gas", 45)]),
+            oil_processing("advanced-oil-processing", vec![fluid("crude-oil", 100), fluid("water", 50)],
+                vec![fluid("heavy-oil", 25), fluid("light-oil", 45), fluid("petroleum-gas", 55)]),
+            Recipe {
+                category: "chemistry".to_owned(),
+                crafting_time: 5.,
+                ..recipe(vec![fluid("petroleum-gas", 20), item("coal", 1)], vec![item("plastic-bar", 2)])
+            },
+        ];
+
+        let solution = kirkmcdonald(&recipes, &machines(&["oil-processing", "chemistry"]), &[("plastic-bar".to_owned(), Rational::from(11))], &WireKind::Belt, &Constants::default()).unwrap();
+        let graph = &solution.graph;
+        let plastic = graph.nodes.iter().position(|n| n.recipe.as_deref() == Some("plastic-bar")).unwrap();
+        let gas: Vec<_> = graph.inputs(plastic).filter(|e| e.item == "petroleum-gas").map(|e| (graph.nodes[e.from].recipe.as_deref(), e.per_second)).collect();
+        assert_eq!(gas, [(Some("advanced-oil-processing"), Rational::from(110))]);
+
+        let used: Vec<_> = solution.recipes.iter().map(|(name, _)| name.as_str()).collect();
+        assert!(used.contains(&"advanced-oil-processing"));
+        assert!(!used.contains(&"basic-oil-processing"));
+        assert!(solution.surplus.contains(&("light-oil".to_owned(), Rational::from(90))));
+        assert!(solution.inputs.contains(&("crude-oil".to_owned(), Rational::from(200))));
+    }
+
+    #[test]
+    fn enrichment_loop() {
+        let recipes = vec![
+            Recipe {
+                name: "kovarex-enrichment-process".to_owned(),
+                category: "centrifuging".to_owned(),
+                crafting_time: 60.,
+                ..recipe(vec![item("uranium-235", 40), item("uranium-238", 5)], vec![item("uranium-235", 41), item("uranium-238", 2)])
+            },
+        ];
+
+        let solution = kirkmcdonald(&recipes, &machines(&["centrifuging"]), &[("uranium-235".to_owned(), Rational::from(1))], &WireKind::Belt, &Constants::default()).unwrap();
+        // what it makes of its own ingredients is fed back
+        let graph = &solution.graph;
+        let inputs: Vec<_> = graph.inputs(0).map(|e| (e.from, e.item.as_str(), e.per_second)).collect();
+        assert_eq!(inputs, [(0, "uranium-235", Rational::from(40)), (0, "uranium-238", Rational::from(2)), (1, "uranium-238", Rational::from(3))]);
+        assert_eq!(graph.nodes[1].building, None);
+        assert!(graph.nodes[0].byproducts.is_empty());
+        assert_eq!(solution.recipes, [("kovarex-enrichment-process".to_owned(), Rational::from(1))]);
+        assert_eq!(solution.inputs, [("uranium-238".to_owned(), Rational::from(3))]);
+    }
+
+    #[test]
+    fn unmapped_category() {
+        let recipes = vec![
+            Recipe { crafting_time: 1., ..recipe(vec![item("iron-plate", 1)], vec![item("iron-gear-wheel", 1)]) },
+            Recipe { category: "modded-smelting".to_owned(), crafting_time: 1., ..recipe(vec![item("iron-ore", 1)], vec![item("iron-plate", 1)]) },
+        ];
+
+        let solution = kirkmcdonald(&recipes, &machines(&["crafting"]), &[("iron-gear-wheel".to_owned(), Rational::from(2))], &WireKind::Belt, &Constants::default()).unwrap();
+        let graph = &solution.graph;
+        assert_eq!(graph.nodes[0].how_many, Rational::from(2));
+        let input = graph.inputs(0).next().unwrap();
+        assert_eq!((input.item.as_str(), graph.nodes[input.from].building), ("iron-plate", None));
+        assert_eq!(solution.inputs, [("iron-plate".to_owned(), Rational::from(2))]);
+        assert_eq!(solution.unmapped_categories, ["modded-smelting"]);
+    }
+
+    #[test]
+    fn shared_intermediates() {
+        let recipes = vec![
+            recipe(vec![item("iron-plate", 2)], vec![item("iron-gear-wheel", 1)]),
+            recipe(vec![item("iron-gear-wheel", 1)], vec![item("inserter", 1)]),
+            recipe(vec![item("iron-gear-wheel", 2), item("iron-plate", 1)], vec![item("transport-belt", 1)]),
+        ];
+
+        let desired = [("inserter".to_owned(), Rational::from(1)), ("transport-belt".to_owned(), Rational::from(1))];
+        let solution = kirkmcdonald(&recipes, &machines(&["crafting"]), &desired, &WireKind::Belt, &Constants::default()).unwrap();
+        let graph = &solution.graph;
+        let products: Vec<_> = graph.edges.iter().filter(|e| e.to.is_none()).map(|e| (e.item.as_str(), e.per_second)).collect();
+        assert_eq!(products, [("inserter", Rational::from(1)), ("transport-belt", Rational::from(1))]);
+        // one gear node feeding both
+        let gear = graph.nodes.iter().position(|n| n.output == "iron-gear-wheel").unwrap();
+        assert_eq!(graph.nodes.iter().filter(|n| n.output == "iron-gear-wheel").count(), 1);
+        assert_eq!(graph.nodes[gear].how_many, Rational::new(3, 2));
+        let consumers: Vec<_> = graph.outputs(gear).map(|e| (graph.nodes[e.to.unwrap()].output.as_str(), e.per_second)).collect();
+        assert_eq!(consumers, [("inserter", Rational::from(1)), ("transport-belt", Rational::from(2))]);
+        let gears: Vec<_> = solution.recipes.iter().filter(|(name, _)| name == "iron-gear-wheel").collect();
+        assert_eq!(gears, [&("iron-gear-wheel".to_owned(), Rational::from(3))]);
+        assert_eq!(solution.inputs, [("iron-plate".to_owned(), Rational::from(7))]);
+    }
+
+    #[test]
+    fn external_inputs() {
+        let recipes = vec![
+            recipe(vec![item("copper-plate", 1)], vec![item("copper-cable", 2)]),
+            recipe(vec![item("iron-plate", 1), item("copper-cable", 3)], vec![item("electronic-circuit", 1)]),
+        ];
+        let mut consts = Constants::default();
+        consts.external = vec!["copper-cable".to_owned()];
+
+        let desired = [("electronic-circuit".to_owned(), Rational::from(2))];
+        let solution = kirkmcdonald(&recipes, &machines(&["crafting"]), &desired, &WireKind::Belt, &consts).unwrap();
+        assert_eq!(solution.recipes, [("electronic-circuit".to_owned(), Rational::from(2))]);
+        assert_eq!(solution.inputs, [("iron-plate".to_owned(), Rational::from(2)), ("copper-cable".to_owned(), Rational::from(6))]);
+        assert!(solution.graph.nodes.iter().all(|n| n.recipe.is_none() || n.output == "electronic-circuit"));
+
+        let desired = [("copper-cable".to_owned(), Rational::from(2))];
+        assert!(kirkmcdonald(&recipes, &machines(&["crafting"]), &desired, &WireKind::Belt, &consts).is_err());
+    }
+
+    #[test]
+    fn costly_recipes() {
+        // thousands of machines are still better than supplying what we could make
+        let recipes = vec![Recipe { crafting_time: 5000., ..recipe(vec![item("iron-plate", 2)], vec![item("iron-gear-wheel", 1)]) }];
+        let desired = [("iron-gear-wheel".to_owned(), Rational::from(1))];
+        let consts = Constants { objective: Objective::Buildings, ..Constants::default() };
+        let solution = kirkmcdonald(&recipes, &machines(&["crafting"]), &desired, &WireKind::Belt, &consts).unwrap();
+        assert_eq!(solution.recipes, [("iron-gear-wheel".to_owned(), Rational::from(1))]);
+        assert_eq!(solution.inputs, [("iron-plate".to_owned(), Rational::from(2))]);
+    }
+
+    #[test]
+    fn objectives() {
+        let recipes = vec![
+            Recipe { name: "frugal-gear-wheel".to_owned(), crafting_time: 10., ..recipe(vec![item("iron-plate", 2)], vec![item("iron-gear-wheel", 1)]) },
+            Recipe { name: "hasty-gear-wheel".to_owned(), crafting_time: 1., ..recipe(vec![item("iron-plate", 4)], vec![item("iron-gear-wheel", 1)]) },
+        ];
+        let desired = [("iron-gear-wheel".to_owned(), Rational::from(1))];
+        let used = |consts: &Constants| {
+            let solution = kirkmcdonald(&recipes, &machines(&["crafting"]), &desired, &WireKind::Belt, consts).unwrap();
+            solution.recipes.into_iter().map(|(name, _)| name).collect::<Vec<_>>()
+        };
+
+        let mut consts = Constants::default();
+        assert_eq!(used(&consts), ["frugal-gear-wheel"]);
+        consts.objective = Objective::Buildings;
+        assert_eq!(used(&consts), ["hasty-gear-wheel"]);
+        consts.objective = Objective::Power;
+        assert_eq!(used(&consts), ["hasty-gear-wheel"]);
+
+        consts.pinned.insert("iron-gear-wheel".to_owned(), "frugal-gear-wheel".to_owned());
+        assert_eq!(used(&consts), ["frugal-gear-wheel"]);
+        consts.pinned.insert("iron-gear-wheel".to_owned(), "iron-stick".to_owned());
+        assert!(kirkmcdonald(&recipes, &machines(&["crafting"]), &desired, &WireKind::Belt, &consts).is_err());
+    }
+}
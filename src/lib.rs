@@ -1,65 +1,157 @@
-use std::env;
+use std::path::PathBuf;
 
+use fehler::throws;
 use num_rational::Rational32;
-use pcb::NeededWire;
+use pcb::{NeededWire, NeededWires};
 
 use crate::consts::Constants;
+use crate::error::Error;
+use crate::kirkmcdonald::Solution;
+use crate::overrides::RecipeOverride;
 use crate::pcb::{Pcb, Entity, Function, Direction};
-use crate::placement::{Placer, BusPlacer};
-
-mod recipe;
-mod kirkmcdonald;
+use crate::placement::{Placer, Placement};
+use crate::ratio::{Limit, Ratio};
+use crate::report::Report;
+use crate::recipe::{Database, Difficulty, Research};
+use crate::routing::RoutingStats;
+
+pub mod recipe;
+pub mod data;
+pub mod kirkmcdonald;
 pub mod pcb;
-mod placement;
+pub mod placement;
 pub mod routing;
-mod render;
-mod consts;
+pub mod render;
+pub mod consts;
+pub mod error;
+pub mod overrides;
+pub mod lp;
+pub mod cache;
+pub mod catalogue;
+pub mod ratio;
+pub mod report;
+
+pub type Rational = Rational32;
+
+/// Input to [`design`]
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub recipe_path: PathBuf,
+    /// Mods (or directories of mods) to load on top of `recipe_path`
+    pub mods: Vec<PathBuf>,
+    /// Directory to cache the parsed recipes in, see [`cache`]
+    pub cache_dir: Option<PathBuf>,
+    pub difficulty: Difficulty,
+    /// Only recipes this unlocks are used, and its bonuses replace those in `constants`
+    pub research: Research,
+    /// Items to produce and how many per second, all in one factory
+    pub targets: Vec<(String, Rational)>,
+    pub constants: Constants,
+    pub overrides: Vec<RecipeOverride>,
+}
 
-type Rational = Rational32;
+impl Config {
+    pub fn new(recipe_path: impl Into<PathBuf>, target: impl Into<String>, per_second: Rational) -> Self {
+        Self::with_targets(recipe_path, vec![(target.into(), per_second)])
+    }
+
+    pub fn with_targets(recipe_path: impl Into<PathBuf>, targets: Vec<(String, Rational)>) -> Self {
+        Config {
+            recipe_path: recipe_path.into(),
+            mods: vec![],
+            cache_dir: None,
+            difficulty: Difficulty::Normal,
+            research: Research::All,
+            targets,
+            constants: Constants::default(),
+            overrides: overrides::builtin(),
+        }
+    }
+}
 
-pub fn run<P: Pcb>(recipe: &str, amount: f64, pathfinder: impl Fn(&mut P, &NeededWire) -> Result<(), ()> + Clone + Send + 'static) {
-    let path = env::args().nth(1).unwrap_or(
-        "recipe".to_string()
-    );
-    let mut recipes = recipe::extract_recipes(path).unwrap();
-    println!("Parsed {} recipes", recipes.len());
+/// Everything [`design`] produced along the way
+#[derive(Debug, Clone)]
+pub struct Design<P> {
+    pub solution: Solution,
+    pub pcb: P,
+    pub wires: NeededWires,
+    /// Edges (ingredient, consumer) the placer fed back to close recipe cycles
+    pub feedback: Vec<(String, String)>,
+    pub routing: RoutingStats,
+    pub report: Report,
+    pub blueprint: String,
+}
 
-    // apply recipe overrides
-    let ec_recipe = recipes.iter_mut().find(|r| r.results.len() == 1 && r.results[0].name == "electronic-circuit").unwrap();
-    let ec_ing = ec_recipe.ingredients.iter_mut().find(|i| i.name == "copper-cable").unwrap();
-    ec_ing.name = "copper-plate".to_string();
-    ec_ing.amount /= 2;
+/// The recipes and prototypes of `config`, without overrides and restricted to its research,
+/// and its constants with the research bonuses
+#[throws]
+pub fn database(config: &Config) -> (Database, Constants) {
+    let mut db = match config.cache_dir {
+        Some(ref dir) => cache::extract_database(dir, &config.recipe_path, &config.mods)?,
+        None => recipe::extract_database(&config.recipe_path, &config.mods)?,
+    };
+    // before looking at what's enabled, which may differ by difficulty
+    db.select(config.difficulty)?;
+    let mut constants = config.constants.clone();
+    if let Some(technologies) = db.researched(&config.research)? {
+        db.retain_unlocked(&technologies);
+        constants.apply_research(&technologies);
+    }
+    (db, constants)
+}
 
-    let desired_per_second = Rational::approximate_float(amount).unwrap();
-    let tree = kirkmcdonald::kirkmcdonald(&recipes, recipe, desired_per_second, &pcb::WireKind::Belt);
-    println!("{:#?}", tree);
+/// Rates of `item` at which every machine is busy, with the recipes and machines of `config`,
+/// see [`ratio::whole_machine_rates`]. Its overrides don't apply, as the merged recipes would
+/// hide the machines of their parts.
+#[throws]
+pub fn ratios(config: &Config, item: &str, limit: Limit, tolerance: Rational) -> Vec<Ratio> {
+    let (db, constants) = database(config)?;
+    ratio::whole_machine_rates(&db.recipes, &db.prototypes, item, limit, tolerance, &constants)?
+}
 
+/// Solve, place and route a factory as described by `config`.
+#[throws]
+pub fn design<P: Pcb, L: Placer>(config: &Config, pathfinder: impl Fn(&mut P, &NeededWire) -> Result<(), ()> + Clone + Send + 'static) -> Design<P> {
+    let (mut db, constants) = database(config)?;
+    overrides::apply(&mut db.recipes, &config.overrides)?;
 
-    let mut pcb = P::default();
-    let needed_wires = BusPlacer::place(&mut pcb, &tree, &Constants::default());
+    let solution = kirkmcdonald::kirkmcdonald(&db.recipes, &db.prototypes, &config.targets, &pcb::WireKind::Belt, &constants)?;
 
-    println!("rendering {} wires", needed_wires.len());
+    let mut pcb = P::default();
+    let Placement { wires, feedback } = L::place(&mut pcb, &solution.graph, &constants, &db.prototypes)?;
 
-    //routing::route(&mut pcb, needed_wires, |pcb, w| routing::mylee(pcb, w, MyleeOptimizations::empty()));
-    routing::route(&mut pcb, needed_wires, pathfinder);
+    let routing = routing::route(&mut pcb, wires.clone(), pathfinder)?;
+    let report = Report::new(&solution.graph, &pcb, &constants, &db.prototypes)?;
+    let blueprint = render::blueprint(&pcb, &constants, &db.prototypes)?;
 
-    println!("{}", render::blueprint(&pcb));
-    println!("{}", render::ascii(&pcb));
+    Design { solution, pcb, wires, feedback, routing, report, blueprint }
 }
 
 #[cfg(test)]
 mod test {
-    use super::pcb::{Pcb, GridPcb, HashmapPcb as HashPcb};
+    use super::{Config, Rational};
+    use super::ratio::Limit;
+    use super::pcb::{Pcb, GridPcb, HashmapPcb as HashPcb, NeededWire};
+    use super::placement::BusPlacer;
     use super::routing::{self, MyleeOptions};
 
+    /// Fluid products and what takes two fluids, which the placers can't build
+    const OIL_PRODUCTS: [&str; 4] = ["petroleum-gas", "sulfur", "sulfuric-acid", "lubricant"];
+
+    fn run<P: Pcb>(recipe: &str, amount: f64, pathfinder: impl Fn(&mut P, &NeededWire) -> Result<(), ()> + Clone + Send + 'static) {
+        let mut config = Config::new("recipe", recipe, Rational::approximate_float(amount).unwrap());
+        config.constants.external = OIL_PRODUCTS.iter().map(|&item| item.to_owned()).collect();
+        super::design::<P, BusPlacer>(&config, pathfinder).unwrap();
+    }
+
 
     #[cfg(feature = "leemaze_lib")]
-    fn run_leemaze<P: Pcb>(recipe: &str, amount: f64) { super::run(recipe, amount, |pcb: &mut P, w| routing::lee_pathfinder(pcb, w)); }
-    fn run_mylee_bad<P: Pcb>(recipe: &str, amount: f64) { super::run(recipe, amount, |pcb: &mut P, w| routing::mylee(pcb, w, MyleeOptions::empty())); }
-    fn run_mylee_bad_preferdir<P: Pcb>(recipe: &str, amount: f64) { super::run(recipe, amount, |pcb: &mut P, w| routing::mylee(pcb, w, MyleeOptions::PREFER_SAME_DIRECTION)); }
-    fn run_mylee_underground_bad<P: Pcb>(recipe: &str, amount: f64) { super::run(recipe, amount, |pcb: &mut P, w| routing::mylee(pcb, w, MyleeOptions::USE_UNDERGROUND_BELTS)); }
-    fn run_good<P: Pcb>(recipe: &str, amount: f64) { super::run(recipe, amount, |pcb: &mut P, w| routing::mylee(pcb, w, MyleeOptions::USE_UNDERGROUND_BELTS | MyleeOptions::VISITED_WITH_DIRECTIONS)); }
-    fn run_mylee_underground_preferdir<P: Pcb>(recipe: &str, amount: f64) { super::run(recipe, amount, |pcb: &mut P, w| routing::mylee(pcb, w, MyleeOptions::USE_UNDERGROUND_BELTS | MyleeOptions::VISITED_WITH_DIRECTIONS | MyleeOptions::PREFER_SAME_DIRECTION)); }
+    fn run_leemaze<P: Pcb>(recipe: &str, amount: f64) { run(recipe, amount, |pcb: &mut P, w| routing::lee_pathfinder(pcb, w)); }
+    fn run_mylee_bad<P: Pcb>(recipe: &str, amount: f64) { run(recipe, amount, |pcb: &mut P, w| routing::mylee(pcb, w, MyleeOptions::empty())); }
+    fn run_mylee_bad_preferdir<P: Pcb>(recipe: &str, amount: f64) { run(recipe, amount, |pcb: &mut P, w| routing::mylee(pcb, w, MyleeOptions::PREFER_SAME_DIRECTION)); }
+    fn run_mylee_underground_bad<P: Pcb>(recipe: &str, amount: f64) { run(recipe, amount, |pcb: &mut P, w| routing::mylee(pcb, w, MyleeOptions::USE_UNDERGROUND_BELTS)); }
+    fn run_good<P: Pcb>(recipe: &str, amount: f64) { run(recipe, amount, |pcb: &mut P, w| routing::mylee(pcb, w, MyleeOptions::USE_UNDERGROUND_BELTS | MyleeOptions::VISITED_WITH_DIRECTIONS)); }
+    fn run_mylee_underground_preferdir<P: Pcb>(recipe: &str, amount: f64) { run(recipe, amount, |pcb: &mut P, w| routing::mylee(pcb, w, MyleeOptions::USE_UNDERGROUND_BELTS | MyleeOptions::VISITED_WITH_DIRECTIONS | MyleeOptions::PREFER_SAME_DIRECTION)); }
 
     #[test] fn automation_0_75_grid() { run_good::<GridPcb>("automation-science-pack", 0.75) }
     #[test] fn automation_0_75_hash() { run_good::<HashPcb>("automation-science-pack", 0.75) }
@@ -79,6 +171,39 @@ mod test {
     #[test] fn chemical_0_10_hash() { run_good::<HashPcb>("chemical-science-pack", 0.10) }
     #[test] fn chemical_0_10_underground_bad() { run_mylee_underground_bad::<GridPcb>("chemical-science-pack", 0.10) } // issue #14
 
+    #[test] fn automation_logistic_0_50_basket() {
+        let targets = vec![("automation-science-pack".to_owned(), Rational::new(1, 2)), ("logistic-science-pack".to_owned(), Rational::new(1, 2))];
+        let config = Config::with_targets("recipe", targets);
+        super::design::<GridPcb, BusPlacer>(&config, |pcb: &mut GridPcb, w| routing::mylee(pcb, w, MyleeOptions::USE_UNDERGROUND_BELTS | MyleeOptions::VISITED_WITH_DIRECTIONS)).unwrap();
+    }
+
+    #[test] fn ratios_of_overridden_recipes() {
+        let dir = std::env::temp_dir().join(format!("factorio-ic-ratios-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dump = dir.join("data-raw-dump.json");
+        std::fs::write(&dump, r#"{
+            "recipe": {
+                "copper-cable": { "name": "copper-cable", "ingredients": [["copper-plate", 1]], "result": "copper-cable", "result_count": 2 },
+                "electronic-circuit": { "name": "electronic-circuit", "ingredients": [["iron-plate", 1], ["copper-cable", 3]], "result": "electronic-circuit" }
+            },
+            "assembling-machine": {
+                "assembling-machine-2": { "name": "assembling-machine-2", "crafting_speed": 0.75, "crafting_categories": ["crafting"],
+                    "collision_box": [[-1.2, -1.2], [1.2, 1.2]], "energy_usage": "150kW", "energy_source": { "type": "electric" } }
+            }
+        }"#).unwrap();
+        // the builtin overrides merge the cable into the circuits
+        let config = Config::new(dump, "electronic-circuit", Rational::from(1));
+        let ratios = super::ratios(&config, "electronic-circuit", Limit::Machines(5), Rational::from(0));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let ratios = ratios.unwrap();
+        assert_eq!(ratios.len(), 1);
+        let mut machines = ratios[0].machines.clone();
+        machines.sort();
+        assert_eq!(machines, [("copper-cable".to_owned(), Rational::from(3)), ("electronic-circuit".to_owned(), Rational::from(2))]);
+        assert_eq!(ratios[0].per_second, Rational::from(3));
+    }
+
     #[test] fn utility_0_10_grid() { run_good::<GridPcb>("utility-science-pack", 0.10) }
     #[test] fn production_0_10_grid() { run_good::<GridPcb>("production-science-pack", 0.10) }
 }
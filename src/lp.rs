@@ -0,0 +1,192 @@
+//! tiny dense two-phase simplex, good enough for a few hundred recipes
+
+use fehler::{throw, throws};
+
+use crate::Rational;
+use crate::error::Error;
+
+const EPSILON: f64 = 1e-9;
+
+/// Minimize `c·x` subject to `a x = b` and `x >= 0`.
+///
+/// Every element of `b` must be non-negative, and all of them finite. Returns `None` if the
+/// problem is infeasible or unbounded.
+#[throws]
+pub fn minimize(a: &[Vec<f64>], b: &[f64], c: &[f64]) -> Option<Vec<f64>> {
+    let m = a.len();
+    let n = c.len();
+    if b.len() != m || a.iter().any(|row| row.len() != n) {
+        throw!(Error::Solver(format!("{} constraints of {} variables don't match {} bounds", m, n, b.len())));
+    }
+    if !a.iter().flatten().chain(b).chain(c).all(|x| x.is_finite()) || b.iter().any(|&x| x < 0.) {
+        throw!(Error::Solver("the linear program has negative or non-finite bounds".to_owned()));
+    }
+
+    // columns: n variables, m artificial variables, rhs
+    let width = n + m + 1;
+    let mut tableau: Vec<Vec<f64>> = (0..m)
+        .map(|i| {
+            let mut row = vec![0.; width];
+            row[..n].copy_from_slice(&a[i]);
+            row[n + i] = 1.;
+            row[width - 1] = b[i];
+            row
+        })
+        .collect();
+    let mut basis: Vec<usize> = (n..n + m).collect();
+
+    // phase 1: get rid of the artificial variables
+    let phase1_costs: Vec<f64> = (0..n + m).map(|j| if j < n { 0. } else { 1. }).collect();
+    if !optimize(&mut tableau, &mut basis, &phase1_costs, n + m) {
+        return None;
+    }
+    let infeasibility: f64 = basis.iter().zip(&tableau).filter(|(&j, _)| j >= n).map(|(_, row)| row[width - 1]).sum();
+    if infeasibility > EPSILON * (1. + b.iter().sum::<f64>()) {
+        return None;
+    }
+
+    // pivot remaining (zero) artificial variables out of the basis where possible;
+    // rows where that's impossible are redundant and can stay as they are
+    for i in 0..m {
+        if basis[i] >= n {
+            if let Some(j) = (0..n).find(|&j| tableau[i][j].abs() > EPSILON) {
+                pivot(&mut tableau, &mut basis, i, j);
+            }
+        }
+    }
+
+    // phase 2: artificial columns may no longer enter
+    if !optimize(&mut tableau, &mut basis, c, n) {
+        return None;
+    }
+
+    let mut x = vec![0.; n];
+    for (i, &j) in basis.iter().enumerate() {
+        if j < n {
+            x[j] = tableau[i][width - 1].max(0.);
+        }
+    }
+    Some(x)
+}
+
+/// Run simplex iterations with Bland's rule. Only the first `enterable` columns may enter the
+/// basis. Returns false if the problem is unbounded.
+fn optimize(tableau: &mut [Vec<f64>], basis: &mut [usize], costs: &[f64], enterable: usize) -> bool {
+    loop {
+        let reduced_cost = |j: usize| costs[j] - basis.iter().zip(tableau.iter()).map(|(&b, row)| costs[b] * row[j]).sum::<f64>();
+        let entering = match (0..enterable).find(|&j| !basis.contains(&j) && reduced_cost(j) < -EPSILON) {
+            Some(j) => j,
+            None => return true,
+        };
+
+        let rhs = tableau[0].len() - 1;
+        let leaving = (0..tableau.len())
+            .filter(|&i| tableau[i][entering] > EPSILON)
+            .min_by(|&i, &k| {
+                let ri = tableau[i][rhs] / tableau[i][entering];
+                let rk = tableau[k][rhs] / tableau[k][entering];
+                ri.total_cmp(&rk).then(basis[i].cmp(&basis[k]))
+            });
+        match leaving {
+            Some(i) => pivot(tableau, basis, i, entering),
+            None => return false,
+        }
+    }
+}
+
+fn pivot(tableau: &mut [Vec<f64>], basis: &mut [usize], row: usize, col: usize) {
+    let p = tableau[row][col];
+    for x in tableau[row].iter_mut() {
+        *x /= p;
+    }
+    let pivot_row = tableau[row].clone();
+    for (i, r) in tableau.iter_mut().enumerate() {
+        let f = r[col];
+        if i != row && f != 0. {
+            for (x, &y) in r.iter_mut().zip(&pivot_row) {
+                *x -= f * y;
+            }
+        }
+    }
+    basis[row] = col;
+}
+
+/// Convert a solver result back into a reasonably simple fraction. Fails if it doesn't fit.
+#[throws]
+pub fn to_rational(x: f64) -> Rational {
+    let out_of_range = || Error::Solver(format!("{} is out of range", x));
+    if !x.is_finite() || x.abs() > i32::MAX as f64 {
+        throw!(out_of_range());
+    }
+
+    // continued fraction expansion until we're close enough
+    let (mut h0, mut h1) = (0i64, 1i64);
+    let (mut k0, mut k1) = (1i64, 0i64);
+    let mut rest = x.abs();
+    for _ in 0..20 {
+        let a = rest.floor();
+        // the first term fits, later ones only get this big once we're close enough anyway
+        if a > i32::MAX as f64 {
+            break;
+        }
+        let (h2, k2) = (a as i64 * h1 + h0, a as i64 * k1 + k0);
+        if h2 > i32::MAX as i64 || k2 > i32::MAX as i64 {
+            break;
+        }
+        h0 = h1;
+        h1 = h2;
+        k0 = k1;
+        k1 = k2;
+        if (x.abs() - h1 as f64 / k1 as f64).abs() <= EPSILON * x.abs().max(1.) || rest - a < EPSILON {
+            break;
+        }
+        rest = 1. / (rest - a);
+    }
+    let numer = i32::try_from(h1).map_err(|_| out_of_range())?;
+    let denom = i32::try_from(k1).map_err(|_| out_of_range())?;
+    Rational::new(numer * x.signum() as i32, denom)
+}
+
+pub fn to_f64(x: Rational) -> f64 {
+    *x.numer() as f64 / *x.denom() as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn simple_lp() {
+        // minimize x + 2y subject to x + y - s = 3, y = 1
+        let a = vec![vec![1., 1., -1.], vec![0., 1., 0.]];
+        let x = minimize(&a, &[3., 1.], &[1., 2., 0.]).unwrap().unwrap();
+        assert!((x[0] - 2.).abs() < 1e-9);
+        assert!((x[1] - 1.).abs() < 1e-9);
+        assert!(x[2].abs() < 1e-9);
+    }
+
+    #[test]
+    fn infeasible_lp() {
+        // x = 1 and x = 2
+        let a = vec![vec![1.], vec![1.]];
+        assert!(minimize(&a, &[1., 2.], &[1.]).unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_lp() {
+        let a = vec![vec![1.]];
+        assert!(minimize(&a, &[-1.], &[1.]).is_err());
+        assert!(minimize(&a, &[f64::NAN], &[1.]).is_err());
+        assert!(minimize(&a, &[1., 2.], &[1.]).is_err());
+    }
+
+    #[test]
+    fn rational_roundtrip() {
+        assert_eq!(to_rational(0.75).unwrap(), Rational::new(3, 4));
+        assert_eq!(to_rational(1. / 3. + 1e-12).unwrap(), Rational::new(1, 3));
+        assert_eq!(to_rational(-2.5).unwrap(), Rational::new(-5, 2));
+        assert_eq!(to_rational(0.).unwrap(), Rational::from(0));
+        assert!(to_rational(1e10).is_err());
+        assert!(to_rational(f64::INFINITY).is_err());
+    }
+}
@@ -1,14 +1,397 @@
-use factorio_ic::run;
-use factorio_ic::pcb::GridPcb;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+use clap::{ArgEnum, Args, Parser, Subcommand};
+
+use factorio_ic::{database, design, ratios, Config, Rational};
+use factorio_ic::catalogue::{self, Catalogue};
+use factorio_ic::consts::{AssemblerTier, BeltType, Objective};
+use factorio_ic::error::{Error, Result};
+use factorio_ic::overrides;
+use factorio_ic::pcb::{Pcb, GridPcb, HashmapPcb, NaivePcb};
+use factorio_ic::placement::{Placer, BusPlacer, SimpleGridPlacer};
+use factorio_ic::ratio::Limit;
+use factorio_ic::recipe::{Difficulty, Recipe, Research};
+use factorio_ic::render;
 use factorio_ic::routing::{self, MyleeOptions};
 
-fn main() {
-    //run("logistic-science-pack", 1., |pcb: &mut GridPcb, f| routing::lee_pathfinder(pcb, f));
-    //run("chemical-science-pack", 0.3, |pcb: &mut GridPcb, f, t| routing::mylee(pcb, f, t, MyleeOptions::USE_UNDERGROUND_BELTS | MyleeOptions::VISITED_WITH_DIRECTIONS));
-    //run("chemical-science-pack", 1., |pcb: &mut GridPcb, f, t| routing::mylee(pcb, f, t, MyleeOptions::USE_UNDERGROUND_BELTS | MyleeOptions::VISITED_WITH_DIRECTIONS));
+#[derive(Parser)]
+#[clap(about = "Designs Factorio factories and renders them as blueprints")]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Solve, place and route one factory for some products
+    Design(DesignArgs),
+    /// Find rates of a product at which every machine runs all the time
+    Ratios(RatiosArgs),
+    /// Look at the loaded recipes
+    Catalogue(CatalogueArgs),
+}
+
+/// Where the recipes come from and which of them are available
+#[derive(Args)]
+struct DataArgs {
+    /// Factorio's data directory, a single mod, a directory containing the recipe prototypes, or
+    /// the data-raw-dump.json written by `factorio --dump-data`
+    #[clap(long, default_value = "recipe")]
+    recipes: PathBuf,
+    /// Mod, or directory of mods, to load on top, may be repeated
+    #[clap(long = "mods")]
+    mods: Vec<PathBuf>,
+    /// Keep the parsed recipes in this directory, and only parse them again when they change
+    #[clap(long)]
+    cache: Option<PathBuf>,
+    /// Use the expensive recipes, like marathon servers do
+    #[clap(long)]
+    expensive: bool,
+    /// Researched technology, may be repeated. Only recipes enabled from the start or unlocked
+    /// by these are used, and their bonuses replace --inserter-capacity-bonus.
+    #[clap(long = "researched", conflicts_with = "research_up_to")]
+    researched: Vec<String>,
+    /// Like --researched with this technology and everything it requires
+    #[clap(long)]
+    research_up_to: Option<String>,
+}
+
+/// How the factory gets built, from the recipes to the modules in the machines
+#[derive(Args)]
+struct BuildArgs {
+    #[clap(flatten)]
+    data: DataArgs,
+    /// Lua file with recipe overrides to use instead of the builtin ones
+    #[clap(long)]
+    overrides: Option<PathBuf>,
+
+    /// Item to take as an input even though we could make it, like iron-gear-wheel off the main
+    /// bus, may be repeated
+    #[clap(long = "external")]
+    external: Vec<String>,
+    /// What to minimize when several recipes make something: buildings, raw-resources or power
+    #[clap(long, default_value = "raw-resources", parse(try_from_str = parse_objective))]
+    objective: Objective,
+    /// Recipe to make an item with, like solid-fuel=solid-fuel-from-light-oil, may be repeated
+    #[clap(long = "recipe", parse(try_from_str = parse_pinned))]
+    pinned: Vec<(String, String)>,
+    /// Assembling machine tier (1-3)
+    #[clap(long, default_value = "2", parse(try_from_str = parse_assembler_tier))]
+    assembler_tier: AssemblerTier,
+    /// Machine for a crafting category, like smelting=steel-furnace, may be repeated
+    #[clap(long = "machine", parse(try_from_str = parse_machine))]
+    machines: Vec<(String, String)>,
+    /// Module to put in every machine slot, may be repeated to fill the slots in order
+    #[clap(long = "module")]
+    modules: Vec<String>,
+    /// Belt to build everything with, e.g. fast-transport-belt
+    #[clap(long, default_value = "transport-belt", parse(try_from_str = parse_belt))]
+    belt: BeltType,
+    /// Researched level of inserter capacity bonus (0-7)
+    #[clap(long, default_value = "7")]
+    inserter_capacity_bonus: i32,
+    /// Beacons affecting each machine
+    #[clap(long, default_value = "0")]
+    beacons: usize,
+    /// Module to put in the beacons, may be repeated
+    #[clap(long = "beacon-module")]
+    beacon_modules: Vec<String>,
+}
+
+#[derive(Args)]
+struct DesignArgs {
+    /// Items to produce, as item or item=rate
+    #[clap(required = true, parse(try_from_str = parse_target))]
+    targets: Vec<(String, Option<Rational>)>,
+    /// Items per second of targets without a rate
+    #[clap(short, long, default_value = "1", parse(try_from_str = parse_rate))]
+    rate: Rational,
+
+    #[clap(flatten)]
+    build: BuildArgs,
+
+    #[clap(long, arg_enum, default_value = "bus")]
+    placer: PlacerKind,
+    #[clap(long, arg_enum, default_value = "mylee")]
+    router: RouterKind,
+    #[clap(long, arg_enum, default_value = "grid")]
+    pcb: PcbKind,
+
+    /// Don't let mylee use underground belts
+    #[clap(long)]
+    no_underground_belts: bool,
+    /// Only track visited tiles, not the direction they were visited from, in mylee
+    #[clap(long)]
+    no_visited_with_directions: bool,
+    /// Let mylee try to keep going in the same direction first
+    #[clap(long)]
+    prefer_same_direction: bool,
+
+    #[clap(short, long, arg_enum, default_value = "blueprint")]
+    format: OutputFormat,
+    /// Write the result to this file instead of stdout
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+    /// Print what the factory takes, draws and is built from to stderr
+    #[clap(long)]
+    report: bool,
+}
 
-    run("utility-science-pack", 0.6, |pcb: &mut GridPcb, w| routing::mylee(pcb, w, MyleeOptions::USE_UNDERGROUND_BELTS | MyleeOptions::VISITED_WITH_DIRECTIONS));
+#[derive(Args)]
+struct RatiosArgs {
+    /// Item to produce
+    target: String,
+    /// Most machines to build
+    #[clap(long, required_unless_present = "max_area", conflicts_with = "max_area")]
+    max_machines: Option<i32>,
+    /// Most tiles the machines may cover
+    #[clap(long)]
+    max_area: Option<i32>,
+    /// Machine time each recipe may leave unused, e.g. 0.1 for a tenth of a machine
+    #[clap(long, default_value = "0", parse(try_from_str = parse_idle))]
+    idle: Rational,
+
+    #[clap(flatten)]
+    build: BuildArgs,
+}
+
+#[derive(Args)]
+struct CatalogueArgs {
+    #[clap(flatten)]
+    data: DataArgs,
+    #[clap(subcommand)]
+    query: Query,
+}
+
+#[derive(Subcommand)]
+enum Query {
+    /// The crafting categories, or the recipes of one
+    Categories { category: Option<String> },
+    /// Recipes that make an item
+    Producers { item: String },
+    /// Recipes that use an item
+    Consumers { item: String },
+    /// What a recipe takes, down to the raw inputs
+    Tree { recipe: String },
+    /// Items no recipe makes, which the solver takes as raw inputs
+    Raw,
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+enum PlacerKind {
+    Bus,
+    SimpleGrid,
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+enum RouterKind {
+    Mylee,
+    #[cfg(feature = "leemaze_lib")]
+    Lee,
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+enum PcbKind {
+    Grid,
+    Hashmap,
+    Naive,
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+enum OutputFormat {
+    Blueprint,
+    Ascii,
+}
+
+fn parse_assembler_tier(s: &str) -> std::result::Result<AssemblerTier, String> {
+    s.parse().ok().and_then(AssemblerTier::from_level).ok_or_else(|| format!("no assembling machine tier {}", s))
+}
 
-    //run("production-science-pack", 45./60., |pcb: &mut GridPcb, w| routing::mylee(pcb, w, MyleeOptions::USE_UNDERGROUND_BELTS | MyleeOptions::VISITED_WITH_DIRECTIONS));
+fn parse_machine(s: &str) -> std::result::Result<(String, String), String> {
+    s.split_once('=').map(|(c, m)| (c.to_owned(), m.to_owned())).ok_or_else(|| format!("expected category=machine, got {}", s))
 }
 
+fn parse_objective(s: &str) -> std::result::Result<Objective, String> {
+    Objective::from_name(s).ok_or_else(|| format!("unknown objective {}", s))
+}
+
+fn parse_pinned(s: &str) -> std::result::Result<(String, String), String> {
+    s.split_once('=').map(|(i, r)| (i.to_owned(), r.to_owned())).ok_or_else(|| format!("expected item=recipe, got {}", s))
+}
+
+fn parse_rate(s: &str) -> std::result::Result<Rational, String> {
+    s.parse::<f64>().ok().filter(|r| r.is_finite() && *r > 0.).and_then(Rational::approximate_float)
+        .ok_or_else(|| format!("expected a positive number of items per second, got {}", s))
+}
+
+fn parse_idle(s: &str) -> std::result::Result<Rational, String> {
+    s.parse::<f64>().ok().filter(|x| (0. ..1.).contains(x)).and_then(Rational::approximate_float)
+        .ok_or_else(|| format!("expected a fraction of a machine from 0 up to 1, got {}", s))
+}
+
+fn parse_target(s: &str) -> std::result::Result<(String, Option<Rational>), String> {
+    match s.split_once('=') {
+        Some((item, rate)) => Ok((item.to_owned(), Some(parse_rate(rate).map_err(|e| format!("bad rate for {}: {}", item, e))?))),
+        None => Ok((s.to_owned(), None)),
+    }
+}
+
+fn parse_belt(s: &str) -> std::result::Result<BeltType, String> {
+    BeltType::from_name(s).ok_or_else(|| format!("unknown belt {}", s))
+}
+
+impl DataArgs {
+    fn config(&self, targets: Vec<(String, Rational)>) -> Config {
+        let mut config = Config::with_targets(&self.recipes, targets);
+        config.mods = self.mods.clone();
+        config.cache_dir = self.cache.clone();
+        if self.expensive {
+            config.difficulty = Difficulty::Expensive;
+        }
+        if let Some(ref tech) = self.research_up_to {
+            config.research = Research::UpTo(tech.clone());
+        } else if !self.researched.is_empty() {
+            config.research = Research::Only(self.researched.clone());
+        }
+        config
+    }
+}
+
+impl BuildArgs {
+    fn config(&self, targets: Vec<(String, Rational)>) -> Result<Config> {
+        let mut config = self.data.config(targets);
+        if let Some(ref path) = self.overrides {
+            config.overrides = overrides::load(path)?;
+        }
+        config.constants.assembler = self.assembler_tier;
+        config.constants.machines = self.machines.iter().cloned().collect();
+        config.constants.external = self.external.clone();
+        config.constants.objective = self.objective;
+        config.constants.pinned = self.pinned.iter().cloned().collect();
+        config.constants.modules = self.modules.clone();
+        config.constants.max_belts = self.belt;
+        config.constants.set_inserter_capacity_level(self.inserter_capacity_bonus);
+        config.constants.beacons = self.beacons;
+        config.constants.beacon_modules = self.beacon_modules.clone();
+        Ok(config)
+    }
+}
+
+impl DesignArgs {
+    fn mylee_options(&self) -> MyleeOptions {
+        let mut opts = MyleeOptions::empty();
+        opts.set(MyleeOptions::USE_UNDERGROUND_BELTS, !self.no_underground_belts);
+        opts.set(MyleeOptions::VISITED_WITH_DIRECTIONS, !self.no_visited_with_directions);
+        opts.set(MyleeOptions::PREFER_SAME_DIRECTION, self.prefer_same_direction);
+        opts
+    }
+}
+
+fn run<P: Pcb, L: Placer>(args: &DesignArgs) -> Result<String> {
+    let targets = args.targets.iter()
+        .map(|(item, rate)| (item.clone(), rate.unwrap_or(args.rate)))
+        .collect();
+    let config = args.build.config(targets)?;
+
+    let result = match args.router {
+        RouterKind::Mylee => {
+            let opts = args.mylee_options();
+            design::<P, L>(&config, move |pcb: &mut P, w| routing::mylee(pcb, w, opts))?
+        }
+        #[cfg(feature = "leemaze_lib")]
+        RouterKind::Lee => design::<P, L>(&config, |pcb: &mut P, w| routing::lee_pathfinder(pcb, w))?,
+    };
+    for category in &result.solution.unmapped_categories {
+        eprintln!("warning: no machine crafts {} recipes, their products are external inputs", category);
+    }
+    for (item, per_second) in &result.solution.surplus {
+        eprintln!("surplus: {} {}/s", item, per_second);
+    }
+    if args.report {
+        eprintln!("{}", result.report);
+    }
+
+    Ok(match args.format {
+        OutputFormat::Blueprint => result.blueprint,
+        OutputFormat::Ascii => render::ascii(&result.pcb),
+    })
+}
+
+fn run_on<L: Placer>(args: &DesignArgs) -> Result<String> {
+    match args.pcb {
+        PcbKind::Grid => run::<GridPcb, L>(args),
+        PcbKind::Hashmap => run::<HashmapPcb, L>(args),
+        PcbKind::Naive => run::<NaivePcb, L>(args),
+    }
+}
+
+fn query(args: &CatalogueArgs) -> Result<String> {
+    let (db, _) = database(&args.data.config(vec![]))?;
+    let catalogue = Catalogue::new(&db.recipes);
+    let lines = |recipes: &[&Recipe]| recipes.iter().map(|r| catalogue::describe(r)).collect::<Vec<_>>().join("\n");
+
+    Ok(match args.query {
+        Query::Categories { category: None } => catalogue.categories().join("\n"),
+        Query::Categories { category: Some(ref category) } => lines(&catalogue.in_category(category)),
+        Query::Producers { ref item } => lines(catalogue.producers(item)),
+        Query::Consumers { ref item } => lines(catalogue.consumers(item)),
+        Query::Tree { ref recipe } => catalogue.ingredient_tree(recipe)?.to_string().trim_end().to_owned(),
+        Query::Raw => catalogue.unproduced().join("\n"),
+    })
+}
+
+fn find_ratios(args: &RatiosArgs) -> Result<String> {
+    let config = args.build.config(vec![])?;
+    let limit = match args.max_machines {
+        Some(machines) => Limit::Machines(machines),
+        None => Limit::Area(args.max_area.unwrap()),
+    };
+    let lines: Vec<_> = ratios(&config, &args.target, limit, args.idle)?.into_iter().map(|ratio| {
+        let machines = ratio.machines.iter().map(|(recipe, x)| format!("{} {}", x, recipe)).collect::<Vec<_>>().join(", ");
+        format!("{}/s: {} machines, {} idle: {}", ratio.per_second, ratio.total, ratio.idle, machines)
+    }).collect();
+    if lines.is_empty() {
+        return Ok(format!("no rate of {} fits, try a bigger limit or more idle time", args.target));
+    }
+    Ok(lines.join("\n"))
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Design(args) => {
+            let result = match args.placer {
+                PlacerKind::Bus => run_on::<BusPlacer>(&args),
+                PlacerKind::SimpleGrid => run_on::<SimpleGridPlacer>(&args),
+            };
+            let written = result.and_then(|result| match args.output {
+                Some(ref path) => fs::write(path, result).map_err(|e| Error::Write(path.clone(), e)),
+                None => {
+                    println!("{}", result);
+                    Ok(())
+                }
+            });
+            if let Err(e) = written {
+                eprintln!("error: {}", e);
+                process::exit(1);
+            }
+        }
+        Command::Ratios(args) => match find_ratios(&args) {
+            Ok(result) => println!("{}", result),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                process::exit(1);
+            }
+        },
+        Command::Catalogue(args) => match query(&args) {
+            Ok(result) => println!("{}", result),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                process::exit(1);
+            }
+        },
+    }
+}
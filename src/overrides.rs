@@ -0,0 +1,167 @@
+//! recipe overrides: merge sub-recipes into compound build blocks
+
+use std::path::Path;
+
+use fehler::{throw, throws};
+use rlua::{Lua, Table};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::recipe::{Ingredient, Recipe};
+
+/// Placer layout for a compound recipe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Template {
+    /// three cable assemblers direct-inserting into two circuit assemblers
+    CableCircuit,
+}
+
+impl Template {
+    pub fn from_name(name: &str) -> Option<Template> {
+        match name {
+            "cable+circuit" => Some(Template::CableCircuit),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Template::CableCircuit => "cable+circuit",
+        }
+    }
+
+    /// Number of merged recipes the layout has room for
+    pub fn num_parts(&self) -> usize {
+        match self {
+            Template::CableCircuit => 1,
+        }
+    }
+
+    /// Number of main assemblers in one instance of the layout
+    pub fn multiplier(&self) -> i32 {
+        match self {
+            Template::CableCircuit => 2,
+        }
+    }
+
+    /// Height of one instance of the layout, in regular bus tiles
+    pub fn tiles(&self) -> i32 {
+        match self {
+            Template::CableCircuit => 5,
+        }
+    }
+}
+
+/// Set on recipes that had other recipes merged into them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Compound {
+    pub template: Template,
+    /// products of the merged recipes
+    pub parts: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecipeOverride {
+    /// product of the recipe to override
+    pub recipe: String,
+    /// ingredients whose recipes get merged in, so that we consume their ingredients instead
+    pub merge: Vec<String>,
+    pub template: Template,
+}
+
+/// The overrides we always used to apply
+pub fn builtin() -> Vec<RecipeOverride> {
+    vec![RecipeOverride {
+        recipe: "electronic-circuit".to_owned(),
+        merge: vec!["copper-cable".to_owned()],
+        template: Template::CableCircuit,
+    }]
+}
+
+/// Load overrides from a Lua file that returns a list like
+/// `{ { recipe = "electronic-circuit", merge = { "copper-cable" }, template = "cable+circuit" } }`
+#[throws]
+pub fn load(path: impl AsRef<Path>) -> Vec<RecipeOverride> {
+    let code = std::fs::read_to_string(path)?;
+    let lua = Lua::new();
+    lua.context(|c| -> Result<_, Error> {
+        let mut overrides = Vec::new();
+        let list: Table = c.load(&code).eval()?;
+        for o in list.sequence_values::<Table>() {
+            let o = o?;
+            let recipe: String = o.get("recipe")?;
+            let merge = o.get::<_, Table>("merge")?.sequence_values().collect::<rlua::Result<_>>()?;
+            let template_name: String = o.get("template")?;
+            let template = Template::from_name(&template_name)
+                .ok_or_else(|| Error::Override(format!("unknown template {:?} for {}", template_name, recipe)))?;
+            overrides.push(RecipeOverride { recipe, merge, template });
+        }
+        Ok(overrides)
+    })?
+}
+
+#[throws]
+pub fn apply(recipes: &mut [Recipe], overrides: &[RecipeOverride]) {
+    for o in overrides {
+        if o.merge.len() != o.template.num_parts() {
+            throw!(Error::Override(format!("template {} needs {} merged recipes for {}", o.template.name(), o.template.num_parts(), o.recipe)));
+        }
+
+        let parts = o.merge.iter().map(|name| {
+            recipes.iter().find(|r| r.results.len() == 1 && &r.results[0].name == name).cloned()
+                .ok_or_else(|| Error::Override(format!("there is no recipe for {} to merge into {}", name, o.recipe)))
+        }).collect::<Result<Vec<_>, _>>()?;
+
+        let recipe = recipes.iter_mut().find(|r| r.results.len() == 1 && r.results[0].name == o.recipe)
+            .ok_or_else(|| Error::Override(format!("there is no recipe for {}", o.recipe)))?;
+        for part in parts {
+            let part_result = &part.results[0];
+            let idx = match recipe.ingredients.iter().position(|i| i.name == part_result.name) {
+                Some(i) => i,
+                None => throw!(Error::Override(format!("{} doesn't use {}", o.recipe, part_result.name))),
+            };
+            let scale = recipe.ingredients.remove(idx).amount / part_result.amount;
+
+            // the part's ingredients take the place of its product
+            let mut at = idx;
+            for ingredient in part.ingredients {
+                let amount = ingredient.amount * scale;
+                let catalyst = ingredient.catalyst * scale;
+                match recipe.ingredients.iter_mut().find(|x| x.name == ingredient.name) {
+                    Some(existing) => {
+                        existing.amount += amount;
+                        existing.catalyst += catalyst;
+                    }
+                    None => {
+                        recipe.ingredients.insert(at, Ingredient { amount, catalyst, ..ingredient });
+                        at += 1;
+                    }
+                }
+            }
+        }
+
+        recipe.compound = Some(Compound { template: o.template, parts: o.merge.clone() });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Rational;
+    use crate::recipe::test_util::{item, recipe};
+
+    #[test]
+    fn merge_cable_into_circuit() {
+        let mut recipes = vec![
+            recipe(vec![item("copper-plate", 1)], vec![item("copper-cable", 2)]),
+            recipe(vec![item("iron-plate", 1), item("copper-cable", 3)], vec![item("electronic-circuit", 1)]),
+        ];
+        apply(&mut recipes, &builtin()).unwrap();
+
+        let ec = &recipes[1];
+        let ingredients: Vec<_> = ec.ingredients.iter().map(|i| (i.name.as_str(), i.amount)).collect();
+        assert_eq!(ingredients, [("iron-plate", Rational::from(1)), ("copper-plate", Rational::new(3, 2))]);
+        assert_eq!(ec.compound.as_ref().unwrap().template, Template::CableCircuit);
+        assert!(recipes[0].compound.is_none());
+    }
+}
@@ -1,9 +1,10 @@
 use ndarray::{s, Array2};
-use fehler::throws;
+use fehler::{throw, throws};
 use std::borrow::Borrow;
 use std::slice::Iter;
 use std::iter::FilterMap;
 
+use crate::error::Error;
 use super::*;
 
 #[derive(Debug, Clone)]
@@ -57,17 +58,23 @@ impl GridPcb {
         self.grid = newgrid;
     }
 
-    #[throws(as Option)]
-    fn place_entity_on_grid(&mut self, entity: &Entity, index: usize) {
+    /// Returns false if the entity doesn't fit on the grid yet
+    #[throws]
+    fn place_entity_on_grid(&mut self, entity: &Entity, index: usize) -> bool {
+        // check everything first so that a conflict doesn't leave half an entity behind
         for tile in entity_tiles(&entity, self.grid_origin) {
-            let tile = self.grid.get_mut((tile.x as usize, tile.y as usize))?;
-            let entities = &self.entities;
-            if (*tile).checked_sub(1).and_then(|i| entities.get(i).and_then(|e| e.as_ref())).is_some() {
-                eprintln!("{}", crate::render::ascii(self));
-                panic!("Conflicting entities");
+            let occupant = match self.grid.get((tile.x as usize, tile.y as usize)) {
+                Some(&i) => i,
+                None => return false,
+            };
+            if occupant.checked_sub(1).and_then(|i| self.entities.get(i).and_then(|e| e.as_ref())).is_some() {
+                throw!(Error::Conflict(tile + self.grid_origin));
             }
-            *tile = index + 1;
         }
+        for tile in entity_tiles(&entity, self.grid_origin) {
+            self.grid[(tile.x as usize, tile.y as usize)] = index + 1;
+        }
+        true
     }
 }
 impl<'a> PcbRef<'a> for GridPcb {
@@ -82,17 +89,24 @@ impl<'a> PcbRef<'a> for GridPcb {
 }
 
 impl Pcb for GridPcb {
+    #[throws]
     fn add(&mut self, entity: impl Borrow<Entity>) {
         let entity = entity.borrow();
         let index = self.entities.len();
+        let prev_rect = self.entity_rect.clone();
         self.entity_rect.update(entity);
 
-        while self.place_entity_on_grid(entity, index).is_none() {
-            self.resize_grid();
+        loop {
+            match self.place_entity_on_grid(entity, index) {
+                Ok(true) => break,
+                Ok(false) => self.resize_grid(),
+                Err(e) => {
+                    self.entity_rect = prev_rect;
+                    throw!(e);
+                }
+            }
         }
 
-        // try to place entity first so that when we retry the placement code it won't get mad
-        // about existing tiles of our entity (which read as None thanks to this)
         self.entities.push(Some(entity.clone()));
     }
 
@@ -120,6 +134,10 @@ impl Pcb for GridPcb {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::sync::Arc;
+    use crate::consts::BeltType;
+    use crate::recipe::{Machine, MachineKind};
+    use crate::recipe::test_util::machine;
 
     fn pcb_invariant(pcb: &GridPcb) {
         let s = pcb.grid.shape();
@@ -139,17 +157,29 @@ mod test {
         let mut pcb = GridPcb::default();
         pcb_invariant(&pcb);
 
-        pcb.add(&Entity { location: Point::new(42, 69), function: Function::Belt(Direction::Up) });
+        pcb.add(&Entity { location: Point::new(42, 69), function: Function::Belt(Direction::Up, BeltType::Normal) }).unwrap();
         dbg!(&pcb);
         pcb_invariant(&pcb);
 
-        pcb.add(&Entity { location: Point::new(0, 0), function: Function::Belt(Direction::Up) });
+        pcb.add(&Entity { location: Point::new(0, 0), function: Function::Belt(Direction::Up, BeltType::Normal) }).unwrap();
         dbg!(&pcb);
         pcb_invariant(&pcb);
 
-        pcb.add(&Entity { location: Point::new(13, 13), function: Function::Belt(Direction::Up) });
+        pcb.add(&Entity { location: Point::new(13, 13), function: Function::Belt(Direction::Up, BeltType::Normal) }).unwrap();
         dbg!(&pcb);
         pcb_invariant(&pcb);
     }
+
+    #[test]
+    fn pcb_conflict() {
+        let mut pcb = GridPcb::default();
+        let machine = Arc::new(Machine { kind: MachineKind::Furnace, ..machine("electric-furnace", &["smelting"]) });
+        pcb.add(&Entity { location: Point::new(0, 0), function: Function::Furnace { machine, modules: vec![] } }).unwrap();
+        assert!(pcb.add(&Entity { location: Point::new(2, 2), function: Function::Belt(Direction::Up, BeltType::Normal) }).is_err());
+        pcb_invariant(&pcb);
+
+        pcb.add(&Entity { location: Point::new(3, 3), function: Function::Belt(Direction::Up, BeltType::Normal) }).unwrap();
+        pcb_invariant(&pcb);
+    }
 }
 
@@ -1,8 +1,10 @@
+use fehler::{throw, throws};
 use fnv::FnvHashMap;
 use std::borrow::Borrow;
 use std::slice::Iter;
 use std::iter::FilterMap;
 
+use crate::error::Error;
 use super::*;
 
 
@@ -20,15 +22,19 @@ impl<'a> PcbRef<'a> for HashmapPcb {
 }
 
 impl Pcb for HashmapPcb {
+    #[throws]
     fn add(&mut self, entity: impl Borrow<Entity>) {
         let entity = entity.borrow();
         let index = self.entities.len();
 
+        if let Some(tile) = entity_tiles(entity, Vector::zeros()).find(|t| self.grid.contains_key(t)) {
+            throw!(Error::Conflict(tile));
+        }
+
         self.entities.push(Some(entity.clone()));
 
         for tile in entity_tiles(entity, Vector::zeros()) {
-            let prev = self.grid.insert(tile, index);
-            assert!(prev.is_none());
+            self.grid.insert(tile, index);
         }
     }
 
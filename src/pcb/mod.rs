@@ -1,7 +1,13 @@
 use nalgebra::geometry::Point2;
 use nalgebra::base::Vector2;
+use serde::{Deserialize, Serialize};
 use std::borrow::Borrow;
 use std::i32;
+use std::sync::Arc;
+
+use crate::consts::BeltType;
+use crate::error::Result;
+use crate::recipe::{Beacon, FluidBox, Machine, Module};
 
 pub type Point = Point2<i32>;
 pub type Vector = Vector2<i32>;
@@ -56,16 +62,33 @@ pub enum InserterKind {
     LongHanded,
     Fast,
     Stack,
+    /// Only marks the inputs of a design
+    Filter,
+}
+impl InserterKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            InserterKind::Normal => "inserter",
+            InserterKind::LongHanded => "long-handed-inserter",
+            InserterKind::Fast => "fast-inserter",
+            InserterKind::Stack => "stack-inserter",
+            InserterKind::Filter => "filter-inserter",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum Function {
-    Assembler { recipe: String },
-    Furnace,
+    Assembler { recipe: String, machine: Arc<Machine>, modules: Vec<Arc<Module>> },
+    Furnace { machine: Arc<Machine>, modules: Vec<Arc<Module>> },
+    ChemicalPlant { recipe: String, machine: Arc<Machine>, orientation: Direction, modules: Vec<Arc<Module>> },
+    OilRefinery { recipe: String, machine: Arc<Machine>, orientation: Direction, modules: Vec<Arc<Module>> },
+    Centrifuge { recipe: String, machine: Arc<Machine>, modules: Vec<Arc<Module>> },
+    Beacon { beacon: Arc<Beacon>, modules: Vec<Arc<Module>> },
     Inserter { orientation: Direction, kind: InserterKind },
-    Belt(Direction),
-    UndergroundBelt(Direction, bool),
-    Splitter(Direction),
+    Belt(Direction, BeltType),
+    UndergroundBelt(Direction, bool, BeltType),
+    Splitter(Direction, BeltType),
     ElectricPole,
     InputMarker(String),
 
@@ -78,22 +101,33 @@ pub struct Entity {
     pub function: Function,
 }
 impl Entity {
+    /// The crafting machine prototype, if this is one
+    pub fn machine(&self) -> Option<&Arc<Machine>> {
+        match self.function {
+            Function::Assembler { ref machine, .. } | Function::Furnace { ref machine, .. } | Function::ChemicalPlant { ref machine, .. }
+            | Function::OilRefinery { ref machine, .. } | Function::Centrifuge { ref machine, .. } => Some(machine),
+            _ => None,
+        }
+    }
+
     pub fn size_x(&self) -> i32 {
         match self.function {
-            Function::Belt(_) | Function::UndergroundBelt(_, _) | Function::Inserter { .. } | Function::ElectricPole
+            Function::Belt(..) | Function::UndergroundBelt(..) | Function::Inserter { .. } | Function::ElectricPole
             | Function::Pipe(_) | Function::UndergroundPipe(_) => 1,
-            Function::Assembler { .. } | Function::Furnace => 3,
+            Function::Assembler { ref machine, .. } | Function::Furnace { ref machine, .. } | Function::ChemicalPlant { ref machine, .. }
+            | Function::OilRefinery { ref machine, .. } | Function::Centrifuge { ref machine, .. } => machine.size,
+            Function::Beacon { .. } => 3,
 
-            Function::Splitter(Direction::Down) | Function::Splitter(Direction::Up) => 2,
-            Function::Splitter(Direction::Left) | Function::Splitter(Direction::Right) => 1,
+            Function::Splitter(Direction::Down, _) | Function::Splitter(Direction::Up, _) => 2,
+            Function::Splitter(Direction::Left, _) | Function::Splitter(Direction::Right, _) => 1,
             Function::InputMarker(_) => 1,
         }
     }
 
     pub fn size_y(&self) -> i32 {
         match self.function {
-            Function::Splitter(Direction::Down) | Function::Splitter(Direction::Up) => 1,
-            Function::Splitter(Direction::Left) | Function::Splitter(Direction::Right) => 2,
+            Function::Splitter(Direction::Down, _) | Function::Splitter(Direction::Up, _) => 1,
+            Function::Splitter(Direction::Left, _) | Function::Splitter(Direction::Right, _) => 2,
 
             _ => self.size_x(), // others are quadratic
         }
@@ -103,6 +137,33 @@ impl Entity {
         Vector::new(self.size_x(), self.size_y())
     }
 
+    /// Tiles next to the entity where pipes connect to its fluid boxes, inputs first and in
+    /// prototype (i.e. recipe) order
+    pub fn fluid_ports(&self) -> (Vec<Point>, Vec<Point>) {
+        let orientation = match self.function {
+            // we always rotate assemblers to the right
+            Function::Assembler { .. } => Direction::Right,
+            Function::ChemicalPlant { orientation, .. } | Function::OilRefinery { orientation, .. } => orientation,
+            _ => Direction::Up,
+        };
+        let (inputs, outputs): (Vec<&FluidBox>, Vec<&FluidBox>) = match self.machine() {
+            Some(machine) => machine.fluid_boxes.iter().partition(|b| b.input),
+            None => (vec![], vec![]),
+        };
+        let size = self.size_x();
+        let rotate = |b: &&FluidBox| {
+            let (x, y) = b.position;
+            let (x, y) = match orientation {
+                Direction::Up => (x, y),
+                Direction::Right => (size - 1 - y, x),
+                Direction::Down => (size - 1 - x, size - 1 - y),
+                Direction::Left => (y, size - 1 - x),
+            };
+            self.location + Vector::new(x, y)
+        };
+        (inputs.iter().map(rotate).collect(), outputs.iter().map(rotate).collect())
+    }
+
     pub fn overlaps(&self, p: Point) -> bool {
         (self.location.x <= p.x)
             && (self.location.x + self.size_x() > p.x)
@@ -140,34 +201,36 @@ pub struct NeededWire {
     pub from: Point,
     pub to: Point,
     pub wire_kind: WireKind,
+    pub belt: BeltType,
 }
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum WireKind {
     Belt,
     Pipe(String),
 }
 impl WireKind {
-    pub fn gap_size(&self) -> usize {
+    pub fn gap_size(&self, belt: BeltType) -> usize {
         match self {
-            WireKind::Belt => 4,
+            WireKind::Belt => belt.underground_gap(),
             WireKind::Pipe(_) => 9,
         }
     }
 }
-pub fn need_belt(from: Point, to: Point) -> NeededWire {
-    NeededWire { from, to, wire_kind: WireKind::Belt }
+pub fn need_belt(from: Point, to: Point, belt: BeltType) -> NeededWire {
+    NeededWire { from, to, wire_kind: WireKind::Belt, belt }
 }
 
 // TODO: Send + 'static bounds should go away eventually
 pub trait Pcb: Default + Clone + Send + 'static where for<'a> Self: PcbRef<'a> {
-    fn add(&mut self, entity: impl Borrow<Entity>);
-    fn add_all<I>(&mut self, iter: I) where I: IntoIterator, I::Item: Borrow<Entity> {
-        for e in iter { self.add(e); }
+    fn add(&mut self, entity: impl Borrow<Entity>) -> Result<()>;
+    fn add_all<I>(&mut self, iter: I) -> Result<()> where I: IntoIterator, I::Item: Borrow<Entity> {
+        for e in iter { self.add(e)?; }
+        Ok(())
     }
     fn remove_at(&mut self, loc: Point);
-    fn replace(&mut self, entity: impl Borrow<Entity>) {
+    fn replace(&mut self, entity: impl Borrow<Entity>) -> Result<()> {
         self.remove_at(entity.borrow().location);
-        self.add(entity);
+        self.add(entity)
     }
 
     fn entity_at(&self, loc: Point) -> Option<&Entity>;
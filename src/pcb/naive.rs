@@ -1,6 +1,8 @@
+use fehler::{throw, throws};
 use std::borrow::Borrow;
 use std::slice::Iter;
 
+use crate::error::Error;
 use super::*;
 
 
@@ -17,10 +19,13 @@ impl<'a> PcbRef<'a> for NaivePcb {
 }
 
 impl Pcb for NaivePcb {
+    #[throws]
     fn add(&mut self, entity: impl Borrow<Entity>) {
         let entity = entity.borrow();
 
-        assert!(self.entity_at(entity.location).is_none());
+        if self.entity_at(entity.location).is_some() {
+            throw!(Error::Conflict(entity.location));
+        }
         self.entities.push(entity.clone());
     }
 
@@ -3,97 +3,106 @@
 use std::cell::RefCell;
 use std::iter;
 
-use crate::consts::Constants;
+use fehler::{throw, throws};
+
+use crate::consts::{Constants, InserterEnd};
+use crate::error::Error;
 use crate::{Entity, Direction, Function, Rational};
 use crate::kirkmcdonald::ProductionGraph;
+use crate::overrides::{Compound, Template};
 use crate::pcb::{Pcb, Point, Vector, NeededWires, need_belt, WireKind, NeededWire, InserterKind};
-use crate::recipe::Category;
-use crate::render;
-use super::Placer;
+use crate::recipe::Prototypes;
+use super::{Placer, Placement, buildings};
 
 use fnv::FnvHashMap;
 use itertools::Itertools;
 use petgraph::prelude::*;
+use petgraph::visit::{depth_first_search, DfsEvent};
 
 pub struct BusPlacer;
 
-static OUTPUT: &'static str = "<output>";
-
 #[derive(Debug, Clone, Copy)]
 struct Edge {
-    num_assemblers: Rational,
     items_per_second: Rational,
 }
 
-/*
-struct ROInputReplacement {
-    from: &'static str,
-    to: &'static str,
-}
-struct RecipeOverride {
-    name: &'static str,
-    multiple: i32,
-    input_replacements: &'static [ROInputReplacement],
-}
-
-fn apply_recipe_overrides(tree: &ProductionGraph) -> ProductionGraph {
-    tree.output
-}*/
-
-
-
 impl Placer for BusPlacer {
-    fn place(pcb: &mut impl Pcb, tree: &ProductionGraph, consts: &Constants) -> NeededWires {
-        // 0. apply recipe overrides
-        //let tree = apply_recipe_overrides(tree);
-
+    #[throws]
+    fn place(pcb: &mut impl Pcb, production: &ProductionGraph, consts: &Constants, prototypes: &Prototypes) -> Placement {
         let mut needed_wires = NeededWires::new();
+        let belt = consts.max_belts;
+
+        // beacons go in an extra row between the machines, so every machine can see at most two
+        if consts.beacons > 2 {
+            throw!(Error::Placement(format!("{} beacons per machine don't fit the bus layout, at most 2 do", consts.beacons)));
+        }
+
+        // one bus node per product that only consumes it
+        let outputs: Vec<String> = production.products.iter().map(|(item, _)| format!("<output {}>", item)).collect();
 
-        // 1. calculate how much we need (i.e. flatten the production graph)
+        // 1. collapse the production graph to the items we build, keyed by item
+        let (functions, _) = buildings(production)?;
         let mut graph = DiGraphMap::<&str, Edge>::new();
         let mut function_map = FnvHashMap::default();
         let mut kind_map = FnvHashMap::default();
-
-        let mut todo_stack = vec![tree];
-        while let Some(item) = todo_stack.pop() {
-            kind_map.insert(item.output.as_str(), item.output_kind.clone());
-
-            if item.building != Some(Category::Assembler) && item.building != Some(Category::Furnace) {
-                continue;
-            }
-
-            todo_stack.extend(&item.inputs);
-
-            for input in &item.inputs {
-                match graph.edge_weight_mut(&input.output, &item.output) {
-                    Some(existing) => {
-                        existing.num_assemblers += input.how_many;
-                        existing.items_per_second += input.per_second;
-                    }
-                    None => {
-                        graph.add_edge(&input.output, &item.output, Edge {
-                            num_assemblers: input.how_many,
-                            items_per_second: input.per_second
-                        });
-                    }
+        let mut compound_map = FnvHashMap::<&str, &Compound>::default();
+        let mut how_many_map = FnvHashMap::<&str, Rational>::default();
+
+        for (node, function) in production.nodes.iter().zip(&functions) {
+            let item = node.output.as_str();
+            kind_map.insert(item, node.output_kind.clone());
+            let function = match function {
+                Some(f) => f,
+                None => continue,
+            };
+            graph.add_node(item);
+            function_map.insert(item, function.clone());
+            how_many_map.insert(item, node.how_many);
+            if let Some(compound) = node.compound.as_ref() {
+                if consts.beacons > 0 {
+                    throw!(Error::Placement(format!("{} is built from a template, which has no room for beacons", item)));
                 }
+                compound_map.insert(item, compound);
             }
-
-            let function = match item.building {
-                Some(Category::Assembler) => Function::Assembler { recipe: item.output.clone() },
-                Some(Category::Furnace) => Function::Furnace,
-                _ => unreachable!(),
+        }
+        for edge in &production.edges {
+            kind_map.insert(edge.item.as_str(), edge.kind.clone());
+            // what the nodes we don't build consume doesn't concern us, and the products come last
+            let to = match edge.to {
+                Some(to) if functions[to].is_some() => production.nodes[to].output.as_str(),
+                _ => continue,
             };
-            function_map.insert(&item.output as &str, function);
+            match graph.edge_weight_mut(edge.item.as_str(), to) {
+                Some(existing) => existing.items_per_second += edge.per_second,
+                None => {
+                    graph.add_edge(edge.item.as_str(), to, Edge { items_per_second: edge.per_second });
+                }
+            }
         }
-        println!("{:#?}", graph);
 
-        let mut order = petgraph::algo::toposort(&graph, None).expect("there are no cyclic recipes"); // unless you're doing uranium, which is currently excluded
-        println!("{:#?}", order);
+        // recipe cycles (e.g. kovarex enrichment) get their back edges fed by recirculating belts,
+        // so they don't count for the build order
+        let mut feedback = Vec::new();
+        let starts: Vec<_> = graph.nodes().filter(|n| !function_map.contains_key(n))
+            .chain(graph.nodes().filter(|n| function_map.contains_key(n)))
+            .collect();
+        depth_first_search(&graph, starts, |event| {
+            if let DfsEvent::BackEdge(from, to) = event {
+                feedback.push((from, to));
+            }
+        });
+        let mut acyclic = graph.clone();
+        for &(from, to) in &feedback {
+            acyclic.remove_edge(from, to);
+        }
+        let mut order = petgraph::algo::toposort(&acyclic, None)
+            .map_err(|c| Error::Placement(format!("{} is part of a recipe cycle", c.node_id())))?;
 
-        graph.add_edge(&tree.output, OUTPUT, Edge { num_assemblers: tree.how_many, items_per_second: tree.per_second });
+        for ((item, per_second), output) in production.products.iter().zip(&outputs) {
+            graph.add_edge(item, output, Edge { items_per_second: *per_second });
+        }
 
-        // 2. build global inputs for the stuff we can't produce (i.e. ores, fluids, right now also chemical plant products like plastic and batteries)
+        // 2. build global inputs for the stuff we don't produce (i.e. ores and external intermediates)
         let mut global_inputs = Vec::new();
         for i in (0..order.len()).rev() {
             if graph.neighbors_directed(order[i], petgraph::Direction::Incoming).count() == 0 {
@@ -103,7 +112,7 @@ impl Placer for BusPlacer {
 
         let mut available_outputs = FnvHashMap::<&str, Vec<Point>>::default();
 
-        let lane_throughput = consts.max_belts.lane_items_per_second();
+        let lane_throughput = prototypes.belt(belt)?.lane_items_per_second;
 
         // 4. bus nodes!
         // Vocabulary:
@@ -152,69 +161,59 @@ impl Placer for BusPlacer {
             }
         }
 
-        let find_inserter_kind = |bw: Rational, force_long: bool, name: &str| -> InserterKind {
+        let find_inserter_kind = |bw: Rational, force_long: bool, from: InserterEnd, to: InserterEnd, name: &str| -> Result<InserterKind, Error> {
             if force_long {
-                if bw > consts.long_inserter_items_per_second() {
-                    panic!("{} throughput of {} is too much for the long inserter!", name, bw);
+                if bw > consts.inserter_items_per_second(prototypes, InserterKind::LongHanded, from, to)? {
+                    return Err(Error::Placement(format!("{} throughput of {} is too much for the long inserter!", name, bw)));
                 }
-                return InserterKind::LongHanded;
+                return Ok(InserterKind::LongHanded);
             }
 
-            if bw <= consts.basic_inserter_items_per_second() {
-                InserterKind::Normal
-            } else if bw <= consts.fast_inserter_items_per_second() {
-                InserterKind::Fast
-            } else if bw <= consts.stack_inserter_items_per_second() {
-                InserterKind::Stack
-            } else {
-                panic!("{} throughput of {} is too much for even a stack inserter!", name, bw);
+            for kind in [InserterKind::Normal, InserterKind::Fast, InserterKind::Stack] {
+                if bw <= consts.inserter_items_per_second(prototypes, kind, from, to)? {
+                    return Ok(kind);
+                }
             }
+            Err(Error::Placement(format!("{} throughput of {} is too much for even a stack inserter!", name, bw)))
         };
 
         let mut bus_nodes = FnvHashMap::default();
         for &recipe in order.iter() {
             let input_edges = graph.neighbors_directed(recipe, petgraph::Direction::Incoming);
             let output_edges = graph.neighbors_directed(recipe, petgraph::Direction::Outgoing);
-            for incoming in input_edges.clone() {
-                println!("[{}] in {} cost={}", recipe, incoming, graph[(incoming, recipe)].num_assemblers);
-            }
 
             let belt_inputs = input_edges.clone().filter(|c| *kind_map.get(c).unwrap() == WireKind::Belt);
             let pipe_input = input_edges.clone().filter(|c| *kind_map.get(c).unwrap() != WireKind::Belt).next();
 
-            let multiplier = if recipe == "electronic-circuit" {
-                Rational::new(2, 1)
-            } else {
-                Rational::from(1)
-            };
+            // compound templates build several assemblers per tile
+            let multiplier = Rational::from(compound_map.get(recipe).map(|c| c.template.multiplier()).unwrap_or(1));
 
-            let howmany_exact = output_edges.clone().map(|x| graph[(recipe, x)].num_assemblers).sum::<Rational>() / multiplier;
+            let howmany_exact = how_many_map[recipe] / multiplier;
 
             let mut inputs: Vec<_> = belt_inputs.clone().map(|i| (i, graph[(i, recipe)])).map(|(i, e)| BusNodeInput { name: i, items_per_second_per_assembler: e.items_per_second / howmany_exact }).collect();
-            let long_inserter_tp = consts.long_inserter_items_per_second();
+            let long_inserter_tp = consts.inserter_items_per_second(prototypes, InserterKind::LongHanded, InserterEnd::Belt(belt), InserterEnd::Machine)?;
             let (_, secondary_belt_inputs) = (0..inputs.len())
                 .combinations(inputs.len().saturating_sub(2))
                 .filter(|c| c.iter().copied().all_unique())
                 .map(|c| (c.iter().map(|&i| inputs[i].items_per_second_per_assembler).sum::<Rational>(), c))
                 .filter(|&(t, _)| t <= long_inserter_tp)
                 .max_by_key(|&(t, _)| t)
-                .expect("Secondary belt input bandwidth too high; long-handed inserter can't keep up!");
+                .ok_or_else(|| Error::Placement(format!("Secondary belt input bandwidth of {} too high; long-handed inserter can't keep up!", recipe)))?;
             for (i, input_idx) in secondary_belt_inputs.into_iter().enumerate() {
                 inputs.swap(i + 2, input_idx);
             }
             let primary_inp_bw: Rational = inputs.iter().take(2).map(|c| c.items_per_second_per_assembler).sum();
-            let primary_inserter_kind = find_inserter_kind(primary_inp_bw, false, "Primary input belt");
+            let primary_inserter_kind = find_inserter_kind(primary_inp_bw, false, InserterEnd::Belt(belt), InserterEnd::Machine, "Primary input belt")?;
 
             let in_max_throughput = belt_inputs.clone().map(|i| graph[(i, recipe)]).map(|e| e.items_per_second / howmany_exact).max().unwrap();
-            let out_throughput = output_edges.clone().map(|o| graph[(recipe, o)]).map(|e| e.items_per_second / e.num_assemblers).next().unwrap() * multiplier;
+            let out_throughput = output_edges.clone().map(|o| graph[(recipe, o)].items_per_second).sum::<Rational>() / howmany_exact;
             let io_max_throughput = std::cmp::max(in_max_throughput, out_throughput);
 
-            let out_serter_kind = find_inserter_kind(out_throughput, pipe_input.is_some(), "Output");
+            let out_serter_kind = find_inserter_kind(out_throughput, pipe_input.is_some(), InserterEnd::Machine, InserterEnd::Belt(belt), "Output")?;
 
             let max_assemblers_per_unit = (lane_throughput / io_max_throughput).floor();
-            println!("[{}] MAPU = {}", recipe, max_assemblers_per_unit);
             if max_assemblers_per_unit < Rational::from(1) {
-                panic!("One assembler of {} produces more output than one lane can handle", recipe);
+                throw!(Error::Placement(format!("One assembler of {} produces more output than one lane can handle", recipe)));
             }
 
             bus_nodes.insert(recipe, BusNode {
@@ -228,18 +227,18 @@ impl Placer for BusPlacer {
                 belt_inbox: RefCell::default(),
             });
         }
-        bus_nodes.insert(OUTPUT, BusNode {
-            max_assemblers_per_unit: 1,
-            num_assemblers_total: Rational::from(1),
-            items_out_per_second_per_assembler: Rational::from(0),
-            belt_inputs: vec![BusNodeInput { name: tree.output.as_str(), items_per_second_per_assembler: Rational::from(0) }],
-            pipe_input: None,
-            primary_inserter_kind: InserterKind::Normal,
-            out_serter_kind: InserterKind::Normal,
-            belt_inbox: RefCell::default(),
-        });
-
-
+        for ((item, _), output) in production.products.iter().zip(&outputs) {
+            bus_nodes.insert(output, BusNode {
+                max_assemblers_per_unit: 1,
+                num_assemblers_total: Rational::from(1),
+                items_out_per_second_per_assembler: Rational::from(0),
+                belt_inputs: vec![BusNodeInput { name: item.as_str(), items_per_second_per_assembler: Rational::from(0) }],
+                pipe_input: None,
+                primary_inserter_kind: InserterKind::Normal,
+                out_serter_kind: InserterKind::Normal,
+                belt_inbox: RefCell::default(),
+            });
+        }
 
         let gap_upper = std::cmp::min(-(bus_nodes.len() as i32), -5);
         let mut input_xoffset = 5;
@@ -251,7 +250,6 @@ impl Placer for BusPlacer {
                 .map(|e| bus_nodes.get(e).unwrap())
                 .flat_map(|n| n.desired_input_belts().filter(|&(k, _)| k == input).map(move |(_, v)| (v, n))).collect();
 
-
             let mut total_instances_needed: i32 = graph.neighbors_directed(input, petgraph::Direction::Outgoing).map(|e| (graph[(input, e)].items_per_second / lane_throughput).ceil().to_integer()).sum();
             if kind == &WireKind::Belt {
                 total_instances_needed = consumers.len() as i32;
@@ -259,45 +257,53 @@ impl Placer for BusPlacer {
 
             for i in 1..total_instances_needed {
                 for j in 0..(total_instances_needed-i-1) {
-                    pcb.add(Entity { location: Point::new(j, -i-1) + Vector::new(input_xoffset, gap_upper), function: Function::Belt(Direction::Down) });
+                    pcb.add(Entity { location: Point::new(j, -i-1) + Vector::new(input_xoffset, gap_upper), function: Function::Belt(Direction::Down, belt) })?;
                 }
-                pcb.add(Entity { location: Point::new(total_instances_needed-1-i, -i-1) + Vector::new(input_xoffset, gap_upper), function: Function::Splitter(Direction::Down) });
+                pcb.add(Entity { location: Point::new(total_instances_needed-1-i, -i-1) + Vector::new(input_xoffset, gap_upper), function: Function::Splitter(Direction::Down, belt) })?;
             }
 
             for i in 0..total_instances_needed {
-                pcb.add(Entity { location: Point::new(i, -1) + Vector::new(input_xoffset, gap_upper), function: Function::Belt(Direction::Down) });
+                pcb.add(Entity { location: Point::new(i, -1) + Vector::new(input_xoffset, gap_upper), function: Function::Belt(Direction::Down, belt) })?;
             }
 
             let input_name = match kind {
                 WireKind::Belt => input.to_owned(),
                 WireKind::Pipe(_) => format!("{}-barrel", input),
             };
-            pcb.add(Entity { location: Point::new(0, -total_instances_needed - 1) + Vector::new(input_xoffset, gap_upper), function: Function::InputMarker(input_name) });
-            pcb.add(Entity { location: Point::new(0, -total_instances_needed - 2) + Vector::new(input_xoffset, gap_upper), function: Function::Belt(Direction::Down) });
+            pcb.add(Entity { location: Point::new(0, -total_instances_needed - 1) + Vector::new(input_xoffset, gap_upper), function: Function::InputMarker(input_name) })?;
+            pcb.add(Entity { location: Point::new(0, -total_instances_needed - 2) + Vector::new(input_xoffset, gap_upper), function: Function::Belt(Direction::Down, belt) })?;
 
             available_outputs.insert(input, (0..total_instances_needed).map(|i| Point::new(i, -1) + Vector::new(input_xoffset, gap_upper)).collect());
 
             input_xoffset += total_instances_needed + 2;
         }
 
-        // 3. global output
-        let global_output_point = Point::new(0, -1) + Vector::new(input_xoffset, gap_upper);
-        pcb.add(Entity { location: global_output_point, function: Function::Belt(Direction::Up) });
-        pcb.add(Entity { location: global_output_point + Vector::new(0, -1), function: Function::Belt(Direction::Up) });
-        pcb.add(Entity { location: global_output_point + Vector::new(0, -2), function: Function::Belt(Direction::Up) });
-
-
-
+        // 3. global outputs, next to the inputs
+        let mut global_output_points = Vec::new();
+        for _ in &production.products {
+            let point = Point::new(0, -1) + Vector::new(input_xoffset, gap_upper);
+            pcb.add(Entity { location: point, function: Function::Belt(Direction::Up, belt) })?;
+            pcb.add(Entity { location: point + Vector::new(0, -1), function: Function::Belt(Direction::Up, belt) })?;
+            pcb.add(Entity { location: point + Vector::new(0, -2), function: Function::Belt(Direction::Up, belt) })?;
+            global_output_points.push(point);
+            input_xoffset += 2;
+        }
 
         let col_vec = Vector::new(12, 0);
-        let tile_vec = Vector::new(0, 4);
+        let tile_h = if consts.beacons > 0 { 7 } else { 4 };
+        let tile_vec = Vector::new(0, tile_h);
+        let beacon = match consts.beacons {
+            0 => None,
+            _ => Some(Function::Beacon {
+                beacon: prototypes.beacon(&consts.beacon)?.clone(),
+                modules: consts.modules_in_beacons(prototypes)?,
+            }),
+        };
         let mut cols_counter = 0;
+        let mut pending_feedback = Vec::new();
         for &recipe in order.iter() {
-            let tile_vec = if recipe == "electronic-circuit" {
-                tile_vec * 5
-            } else {
-                tile_vec
-            };
+            let compound = compound_map.get(recipe).copied();
+            let tile_vec = tile_vec * compound.map(|c| c.template.tiles()).unwrap_or(1);
 
             let output_edges = graph.neighbors_directed(recipe, petgraph::Direction::Outgoing);
 
@@ -313,9 +319,11 @@ impl Placer for BusPlacer {
             let ox = node.pipe_input.is_some() as i32;
 
             let mut consumers: Vec<_> = output_edges.clone()
-                .map(|e| bus_nodes.get(e).unwrap())
-                .flat_map(|n| n.desired_input_belts().filter(|&(k, _)| k == recipe).map(move |(_, v)| (v, n))).collect();
-            consumers.sort_by_key(|x| x.0); // sort biggest consumers to the back (where we start popping)
+                .map(|e| (bus_nodes.get(e).unwrap(), feedback.contains(&(recipe, e))))
+                .flat_map(|(n, fb)| n.desired_input_belts().filter(|&(k, _)| k == recipe).map(move |(_, v)| (v, n, fb))).collect();
+            // sort biggest consumers to the back (where we start popping), but serve feedback loops
+            // first so they can never starve themselves
+            consumers.sort_by_key(|x| (x.2, x.0));
             // here we employ the following algorithm:
             // - sort biggest consumers first
             // - for each subunit, look at the start of this list
@@ -336,37 +344,37 @@ impl Placer for BusPlacer {
             // split this into multiple units if needed due to belt throughput bottlenecks
             for howmany_total in node.units() {
                 let howmany_total = howmany_total.ceil().to_integer();
-                println!("[{}] {} assemblers", recipe, howmany_total);
 
                 let col_start = col_vec * (cols_counter as i32);
 
-                println!("{} {}", recipe, howmany_total);
                 for i in 0..howmany_total {
                     let tile_start = col_start + tile_vec * i;
                     if node.num_distinct_inputs() > 2 {
                         // extra input belt and long inserter
-                        pcb.add_all(&[
-                            Entity { location: Point::new(0, 0) + tile_start, function: Function::Belt(Direction::Down) },
-                            Entity { location: Point::new(0, 1) + tile_start, function: Function::Belt(Direction::Down) },
-                            Entity { location: Point::new(0, 2) + tile_start, function: Function::Belt(Direction::Down) },
-                            Entity { location: Point::new(0, 3) + tile_start, function: Function::Belt(Direction::Down) },
-                            Entity { location: Point::new(2, 1) + tile_start, function: Function::Inserter { orientation: Direction::Right, kind: InserterKind::LongHanded } },
-                        ]);
+                        for y in 0..tile_h {
+                            pcb.add(Entity { location: Point::new(0, y) + tile_start, function: Function::Belt(Direction::Down, belt) })?;
+                        }
+                        pcb.add(Entity { location: Point::new(2, 1) + tile_start, function: Function::Inserter { orientation: Direction::Right, kind: InserterKind::LongHanded } })?;
                     }
 
-                    if recipe == "electronic-circuit" {
+                    if let Some(Compound { template: Template::CableCircuit, parts }) = compound {
+                        let cable = &parts[0];
+                        let (machine, modules) = match &function_map[recipe] {
+                            Function::Assembler { machine, modules, .. } => (machine.clone(), modules.clone()),
+                            _ => throw!(Error::Placement(format!("{} is built from a template for assemblers", recipe))),
+                        };
                         for i in 0..(4*5) {
-                            pcb.add(Entity { location: Point::new(1, i) + tile_start, function: Function::Belt(Direction::Down) });
-                            pcb.add(Entity { location: Point::new(7, i) + tile_start, function: Function::Belt(Direction::Up) });
+                            pcb.add(Entity { location: Point::new(1, i) + tile_start, function: Function::Belt(Direction::Down, belt) })?;
+                            pcb.add(Entity { location: Point::new(7, i) + tile_start, function: Function::Belt(Direction::Up, belt) })?;
                         }
                         for i in 0..5 {
-                            pcb.add(Entity { location: Point::new(2, 2 + 4*i) + tile_start, function: Function::Inserter { orientation: Direction::Right, kind: InserterKind::Normal } });
-                            pcb.add(Entity { location: Point::new(2, 3 + 4*i) + tile_start, function: Function::ElectricPole });
-                            pcb.add(Entity { location: Point::new(6, 3 + 4*i) + tile_start, function: Function::ElectricPole });
+                            pcb.add(Entity { location: Point::new(2, 2 + 4*i) + tile_start, function: Function::Inserter { orientation: Direction::Right, kind: InserterKind::Normal } })?;
+                            pcb.add(Entity { location: Point::new(2, 3 + 4*i) + tile_start, function: Function::ElectricPole })?;
+                            pcb.add(Entity { location: Point::new(6, 3 + 4*i) + tile_start, function: Function::ElectricPole })?;
                         }
 
-                        let di_mid = find_inserter_kind(Rational::new(3, 2), false, "EC direct insert mid");
-                        let di_outer = find_inserter_kind(Rational::new(3, 1), false, "EC direct insert outer");
+                        let di_mid = find_inserter_kind(Rational::new(3, 2), false, InserterEnd::Machine, InserterEnd::Machine, "EC direct insert mid")?;
+                        let di_outer = find_inserter_kind(Rational::new(3, 1), false, InserterEnd::Machine, InserterEnd::Machine, "EC direct insert outer")?;
                         pcb.add_all(&[
                             // outserters
                             Entity { location: Point::new(6, 2 + 4*1) + tile_start, function: Function::Inserter { orientation: Direction::Right, kind: InserterKind::Normal } },
@@ -378,56 +386,67 @@ impl Placer for BusPlacer {
                             Entity { location: Point::new(4, 3 + 4*2) + tile_start, function: Function::Inserter { orientation: Direction::Down, kind: di_mid } },
                             Entity { location: Point::new(4, 3 + 4*3) + tile_start, function: Function::Inserter { orientation: Direction::Up, kind: di_outer } },
 
-                            Entity { location: Point::new(3, 4*1) + tile_start, function: Function::Assembler { recipe: "electronic-circuit".to_owned() } },
-                            Entity { location: Point::new(3, 4*3) + tile_start, function: Function::Assembler { recipe: "electronic-circuit".to_owned() } },
-                            Entity { location: Point::new(3, 4*0) + tile_start, function: Function::Assembler { recipe: "copper-cable".to_owned() } },
-                            Entity { location: Point::new(3, 4*2) + tile_start, function: Function::Assembler { recipe: "copper-cable".to_owned() } },
-                            Entity { location: Point::new(3, 4*4) + tile_start, function: Function::Assembler { recipe: "copper-cable".to_owned() } },
-                        ]);
+                            Entity { location: Point::new(3, 4*1) + tile_start, function: Function::Assembler { recipe: recipe.to_owned(), machine: machine.clone(), modules: modules.clone() } },
+                            Entity { location: Point::new(3, 4*3) + tile_start, function: Function::Assembler { recipe: recipe.to_owned(), machine: machine.clone(), modules: modules.clone() } },
+                            Entity { location: Point::new(3, 4*0) + tile_start, function: Function::Assembler { recipe: cable.clone(), machine: machine.clone(), modules: modules.clone() } },
+                            Entity { location: Point::new(3, 4*2) + tile_start, function: Function::Assembler { recipe: cable.clone(), machine: machine.clone(), modules: modules.clone() } },
+                            Entity { location: Point::new(3, 4*4) + tile_start, function: Function::Assembler { recipe: cable.clone(), machine: machine.clone(), modules: modules.clone() } },
+                        ])?;
                     } else {
+                        // chemical plants have fluid ports where the outserter usually goes
+                        let outserter_y = if let Function::ChemicalPlant { .. } = function_map[recipe] { 1 } else { 2 };
+
                         // primary components: assembler, electricity, belts, inserters
+                        for y in 0..tile_h {
+                            pcb.add(Entity { location: Point::new(1, y) + tile_start, function: Function::Belt(Direction::Down, belt) })?;
+                            pcb.add(Entity { location: Point::new(7 + ox, y) + tile_start, function: Function::Belt(Direction::Up, belt) })?;
+                        }
                         pcb.add_all(&[
-                            Entity { location: Point::new(1, 0) + tile_start, function: Function::Belt(Direction::Down) },
-                            Entity { location: Point::new(1, 1) + tile_start, function: Function::Belt(Direction::Down) },
-                            Entity { location: Point::new(1, 2) + tile_start, function: Function::Belt(Direction::Down) },
-                            Entity { location: Point::new(1, 3) + tile_start, function: Function::Belt(Direction::Down) },
-                            Entity { location: Point::new(7 + ox, 0) + tile_start, function: Function::Belt(Direction::Up) },
-                            Entity { location: Point::new(7 + ox, 1) + tile_start, function: Function::Belt(Direction::Up) },
-                            Entity { location: Point::new(7 + ox, 2) + tile_start, function: Function::Belt(Direction::Up) },
-                            Entity { location: Point::new(7 + ox, 3) + tile_start, function: Function::Belt(Direction::Up) },
-
                             Entity { location: Point::new(2, 2) + tile_start, function: Function::Inserter { orientation: Direction::Right, kind: node.primary_inserter_kind } },
-                            Entity { location: Point::new(6, 2) + tile_start, function: Function::Inserter { orientation: Direction::Right, kind: node.out_serter_kind } },
+                            Entity { location: Point::new(6, outserter_y) + tile_start, function: Function::Inserter { orientation: Direction::Right, kind: node.out_serter_kind } },
                             Entity { location: Point::new(3, 0) + tile_start, function: function_map[recipe].clone() },
                             Entity { location: Point::new(2, 3) + tile_start, function: Function::ElectricPole },
                             Entity { location: Point::new(6, 3) + tile_start, function: Function::ElectricPole },
-                        ]);
+                        ])?;
+
+                        // a beacon below every machine reaches the next one as well, so with one
+                        // beacon per machine we only need every other one
+                        if let Some(ref beacon) = beacon {
+                            if consts.beacons == 2 || i % 2 == 0 {
+                                pcb.add(Entity { location: Point::new(3, 4) + tile_start, function: beacon.clone() })?;
+                            }
+                            if consts.beacons == 2 && i == 0 {
+                                pcb.add_all(&[
+                                    Entity { location: Point::new(3, -3) + tile_start, function: beacon.clone() },
+                                    Entity { location: Point::new(6, -1) + tile_start, function: Function::ElectricPole },
+                                ])?;
+                            }
+                        }
                     }
 
                     // fluid input to the right
                     if let Some(pipe_in) = node.pipe_input {
-                        pcb.add_all(&[
-                            Entity { location: Point::new(7, 0) + tile_start, function: Function::Pipe(pipe_in.to_owned()) },
-                            Entity { location: Point::new(7, 1) + tile_start, function: Function::Pipe(pipe_in.to_owned()) },
-                            Entity { location: Point::new(6, 1) + tile_start, function: Function::Pipe(pipe_in.to_owned()) },
-                            Entity { location: Point::new(7, 2) + tile_start, function: Function::Pipe(pipe_in.to_owned()) },
-                            Entity { location: Point::new(7, 3) + tile_start, function: Function::Pipe(pipe_in.to_owned()) },
-                        ]);
+                        let building = Entity { location: Point::new(3, 0) + tile_start, function: function_map[recipe].clone() };
+                        let port = building.fluid_ports().0[0];
+                        for y in 0..tile_h {
+                            pcb.add(Entity { location: Point::new(7, y) + tile_start, function: Function::Pipe(pipe_in.to_owned()) })?;
+                        }
+                        pcb.add(Entity { location: port, function: Function::Pipe(pipe_in.to_owned()) })?;
                     }
                 }
 
                 let input_points = if node.num_distinct_inputs() > 1 {
                     // combine two input lanes on primary input belt
-                    pcb.replace(Entity { location: Point::new(0, 0) + col_start, function: Function::Belt(Direction::Right) });
-                    pcb.replace(Entity { location: Point::new(2, 0) + col_start, function: Function::Belt(Direction::Left) });
+                    pcb.replace(Entity { location: Point::new(0, 0) + col_start, function: Function::Belt(Direction::Right, belt) })?;
+                    pcb.replace(Entity { location: Point::new(2, 0) + col_start, function: Function::Belt(Direction::Left, belt) })?;
                     let mut points = vec![Point::new(0, 0), Point::new(2, 0)];
                     if node.num_distinct_inputs() > 2 {
-                        pcb.replace(Entity { location: Point::new(0, 3) + col_start + tile_vec * (howmany_total - 1), function: Function::Belt(Direction::Up) });
+                        pcb.replace(Entity { location: Point::new(0, tile_h - 1) + col_start + tile_vec * (howmany_total - 1), function: Function::Belt(Direction::Up, belt) })?;
                         if node.num_distinct_inputs() > 3 {
                             // combine two input lanes on secondary input belt
-                            pcb.replace(Entity { location: Point::new(-1, 0) + col_start, function: Function::Belt(Direction::Down) });
-                            pcb.replace(Entity { location: Point::new(-1, 1) + col_start, function: Function::Belt(Direction::Right) });
-                            pcb.replace(Entity { location: Point::new(-1, 2) + col_start, function: Function::Belt(Direction::Up) });
+                            pcb.replace(Entity { location: Point::new(-1, 0) + col_start, function: Function::Belt(Direction::Down, belt) })?;
+                            pcb.replace(Entity { location: Point::new(-1, 1) + col_start, function: Function::Belt(Direction::Right, belt) })?;
+                            pcb.replace(Entity { location: Point::new(-1, 2) + col_start, function: Function::Belt(Direction::Up, belt) })?;
                             points.extend(&[Point::new(-1, 0), Point::new(-1, 2)]);
                         } else {
                             // secondary input belt is a single lane
@@ -440,7 +459,6 @@ impl Placer for BusPlacer {
                     vec![Point::new(1, 0)]
                 };
 
-
                 // request wire connections towards our belt inputs
                 for (input_name, input_point) in node.belt_inputs().zip(input_points) {
                     let direct_feed = node.belt_inbox.borrow_mut().get_mut(input_name).and_then(|ol| ol.pop());
@@ -450,7 +468,11 @@ impl Placer for BusPlacer {
                             from,
                             to: input_point + col_start,
                             wire_kind: kind_map.get(input_name).unwrap().clone(),
+                            belt,
                         });
+                    } else if function_map.contains_key(input_name) {
+                        // produced further down the bus, connect once it's there
+                        pending_feedback.push((node, input_name, input_point + col_start));
                     }
                 }
                 // fluid inputs as well
@@ -471,12 +493,13 @@ impl Placer for BusPlacer {
                             from,
                             to,
                             wire_kind: WireKind::Pipe(pipe_input.to_owned()),
+                            belt,
                         });
                     }
                 }
 
                 // safely terminate primary input belt
-                pcb.replace(Entity { location: Point::new(1, -1) + col_start + tile_vec * howmany_total, function: Function::Belt(Direction::Up) });
+                pcb.replace(Entity { location: Point::new(1, -1) + col_start + tile_vec * howmany_total, function: Function::Belt(Direction::Up, belt) })?;
 
                 let mut flow = node.items_out_per_second_per_assembler * howmany_total;
                 if let Some(carry) = output_belt_carry.as_ref() {
@@ -484,15 +507,16 @@ impl Placer for BusPlacer {
                     needed_wires.push(NeededWire {
                         from: carry.end,
                         to,
-                        wire_kind: WireKind::Belt
+                        wire_kind: WireKind::Belt,
+                        belt,
                     });
-                    pcb.add(Entity { location: to, function: Function::Belt(Direction::Down) });
+                    pcb.add(Entity { location: to, function: Function::Belt(Direction::Down, belt) })?;
                     flow += carry.flow;
                 }
 
                 let mut consumers_here = Vec::new();
                 let mut num_output_paths = 0;
-                while let Some(&(consumer_flow, consumer)) = consumers.last() {
+                while let Some(&(consumer_flow, consumer, _)) = consumers.last() {
                     if consumer_flow <= flow {
                         consumers.pop();
                         flow -= consumer_flow;
@@ -515,26 +539,26 @@ impl Placer for BusPlacer {
                 }
 
                 // split up outputs
-                pcb.replace(Entity { location: Point::new(7 + ox, 0) + col_start, function: Function::Belt(Direction::Right) });
-                pcb.add(Entity { location: Point::new(8 + ox, 0) + col_start, function: Function::Belt(Direction::Down) });
+                pcb.replace(Entity { location: Point::new(7 + ox, 0) + col_start, function: Function::Belt(Direction::Right, belt) })?;
+                pcb.add(Entity { location: Point::new(8 + ox, 0) + col_start, function: Function::Belt(Direction::Down, belt) })?;
                 let mut output_nodes = Vec::new();
                 for i in 1..num_output_paths {
                     let tile_start = col_start + Vector::new(8 + ox, i * 2 - 1);
                     pcb.add_all(&[
-                        Entity { location: Point::new(0, 0) + tile_start, function: Function::Splitter(Direction::Down) },
-                        Entity { location: Point::new(0, 1) + tile_start, function: Function::Belt(Direction::Down) },
-                        Entity { location: Point::new(1, 1) + tile_start, function: Function::Belt(Direction::Right) },
-                    ]);
+                        Entity { location: Point::new(0, 0) + tile_start, function: Function::Splitter(Direction::Down, belt) },
+                        Entity { location: Point::new(0, 1) + tile_start, function: Function::Belt(Direction::Down, belt) },
+                        Entity { location: Point::new(1, 1) + tile_start, function: Function::Belt(Direction::Right, belt) },
+                    ])?;
                     output_nodes.push(Point::new(1, 1) + tile_start);
                 }
 
                 if synth_splitter_for_carry_in {
-                    pcb.replace(Entity { location: Point::new(8+ox, 2) + col_start, function: Function::Belt(Direction::Up) });
+                    pcb.replace(Entity { location: Point::new(8+ox, 2) + col_start, function: Function::Belt(Direction::Up, belt) })?;
                 } else {
                     pcb.add_all(&[
-                        Entity { location: Point::new(8 + ox, num_output_paths * 2 - 1) + col_start, function: Function::Belt(Direction::Right) },
-                        Entity { location: Point::new(9 + ox, num_output_paths * 2 - 1) + col_start, function: Function::Belt(Direction::Right) },
-                    ]);
+                        Entity { location: Point::new(8 + ox, num_output_paths * 2 - 1) + col_start, function: Function::Belt(Direction::Right, belt) },
+                        Entity { location: Point::new(9 + ox, num_output_paths * 2 - 1) + col_start, function: Function::Belt(Direction::Right, belt) },
+                    ])?;
                     let default_out_point = Point::new(9 + ox, num_output_paths * 2 - 1) + col_start;
                     output_nodes.push(default_out_point);
                 }
@@ -548,34 +572,112 @@ impl Placer for BusPlacer {
                     output_belt_carry = None;
                 }
 
-
-                //output_nodes.reverse();
-                //consumers_here.reverse();
-
-                //available_outputs.entry(recipe).or_default().extend_from_slice(&output_nodes);
-                assert_eq!(output_nodes.len(), consumers_here.len());
+                if output_nodes.len() != consumers_here.len() {
+                    throw!(Error::Placement(format!("{} has {} outputs for {} consumers", recipe, output_nodes.len(), consumers_here.len())));
+                }
                 for (point, customer) in output_nodes.into_iter().zip(consumers_here) {
                     customer.belt_inbox.borrow_mut().entry(recipe).or_default().insert(0, point);
                 }
 
-                for outgoing in output_edges.clone() {
-                    println!("[{}] out {} cost={}", recipe, outgoing, graph[(recipe, outgoing)].num_assemblers);
-                }
                 cols_counter += 1;
             }
-            println!("{:?}", consumers);
-            assert!(consumers.is_empty());
+            if !consumers.is_empty() {
+                let rates = consumers.iter().map(|x| x.0.to_string()).collect::<Vec<_>>().join(", ");
+                throw!(Error::Placement(format!("{} can't supply consumers of {}/s", recipe, rates)));
+            }
         }
 
-        // 5. wire up the output to the last bus node
-        // (can't do this earlier because the output belt's exact position is only known here)
-        //let final_output_belt = available_outputs.get_mut(&tree.output as &str).unwrap().pop().unwrap();
-        let final_output_belt = *bus_nodes.get(OUTPUT).unwrap().belt_inbox.borrow().get(tree.output.as_str()).unwrap().last().unwrap();
-        needed_wires.push(need_belt(final_output_belt, global_output_point));
+        // 5. close the feedback loops now that everything has been built
+        for (node, input_name, to) in pending_feedback {
+            let from = node.belt_inbox.borrow_mut().get_mut(input_name).and_then(|ol| ol.pop())
+                .ok_or_else(|| Error::Placement(format!("nothing feeds {} back", input_name)))?;
+            needed_wires.push(NeededWire { from, to, wire_kind: kind_map.get(input_name).unwrap().clone(), belt });
+        }
 
-        println!("{}", render::ascii(pcb));
+        // 6. wire up the outputs to their bus nodes
+        // (can't do this earlier because the output belts' exact positions are only known here)
+        for (((item, _), output), global_output_point) in production.products.iter().zip(&outputs).zip(global_output_points) {
+            let final_output_belt = *bus_nodes[output.as_str()].belt_inbox.borrow().get(item.as_str()).and_then(|points| points.last())
+                .ok_or_else(|| Error::Placement(format!("don't know how to build {}", item)))?;
+            needed_wires.push(need_belt(final_output_belt, global_output_point, belt));
+        }
 
-        needed_wires
+        let feedback = feedback.into_iter().map(|(from, to)| (from.to_owned(), to.to_owned())).collect();
+        Placement { wires: needed_wires, feedback }
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use crate::kirkmcdonald::kirkmcdonald;
+    use crate::pcb::{GridPcb, PcbRef};
+    use crate::recipe::{Beacon, Belt, Inserter, Machine, MachineKind, Recipe};
+    use crate::recipe::test_util::{item, machine, recipe};
+
+    /// Yellow belts, every inserter and `machines`
+    fn prototypes(machines: Vec<Machine>) -> Prototypes {
+        let mut prototypes = Prototypes::default();
+        for machine in machines {
+            prototypes.machines.insert(machine.name.clone(), Arc::new(machine));
+        }
+        prototypes.belts.insert("transport-belt".to_owned(), Belt {
+            name: "transport-belt".to_owned(),
+            lane_items_per_second: Rational::new(15, 2),
+            underground: Some("underground-belt".to_owned()),
+            splitter: Some("splitter".to_owned()),
+        });
+        for kind in [InserterKind::Normal, InserterKind::LongHanded, InserterKind::Fast, InserterKind::Stack] {
+            prototypes.inserters.insert(kind.name().to_owned(), Inserter {
+                name: kind.name().to_owned(),
+                rotation_speed: Rational::new(1, 25),
+                stack: kind == InserterKind::Stack,
+                energy_usage: Rational::from(20),
+                drain: Rational::new(2, 5),
+                pollution: Rational::from(0),
+            });
+        }
+        prototypes
+    }
+
+    #[test]
+    fn recipe_cycles() {
+        let kovarex = Recipe {
+            name: "kovarex-enrichment-process".to_owned(),
+            category: "centrifuging".to_owned(),
+            crafting_time: 60.,
+            ..recipe(vec![item("uranium-235", 40), item("uranium-238", 5)], vec![item("uranium-235", 41), item("uranium-238", 2)])
+        };
+        let prototypes = prototypes(vec![Machine { kind: MachineKind::Centrifuge, ..machine("centrifuge", &["centrifuging"]) }]);
+        let consts = Constants::default();
+        let solution = kirkmcdonald(&[kovarex], &prototypes, &[("uranium-235".to_owned(), Rational::new(1, 10))], &WireKind::Belt, &consts).unwrap();
+
+        let mut pcb = GridPcb::default();
+        let placement = BusPlacer::place(&mut pcb, &solution.graph, &consts, &prototypes).unwrap();
+        assert_eq!(placement.feedback, [("uranium-235".to_owned(), "uranium-235".to_owned())]);
+        assert_eq!(pcb.entities().filter(|e| matches!(e.function, Function::Centrifuge { .. })).count(), 6);
+    }
+
+    #[test]
+    fn beacons_between_machines() {
+        let gear = recipe(vec![item("iron-plate", 1)], vec![item("iron-gear-wheel", 1)]);
+        let mut prototypes = prototypes(vec![machine("assembler", &["crafting"])]);
+        prototypes.beacons.insert("beacon".to_owned(), Arc::new(Beacon {
+            name: "beacon".to_owned(),
+            energy_usage: Rational::from(480),
+            pollution: Rational::from(0),
+        }));
+        let consts = Constants { beacons: 1, ..Constants::default() };
+        // three machines at two gears per second each
+        let solution = kirkmcdonald(&[gear], &prototypes, &[("iron-gear-wheel".to_owned(), Rational::from(6))], &WireKind::Belt, &consts).unwrap();
+
+        let mut pcb = GridPcb::default();
+        BusPlacer::place(&mut pcb, &solution.graph, &consts, &prototypes).unwrap();
+        let count = |f: fn(&Function) -> bool| pcb.entities().filter(|e| f(&e.function)).count();
+        assert_eq!(count(|f| matches!(f, Function::Assembler { .. })), 3);
+        // one beacon reaches two machines
+        assert_eq!(count(|f| matches!(f, Function::Beacon { beacon, .. } if beacon.name == "beacon")), 2);
+        assert_eq!(count(|f| matches!(f, Function::ElectricPole)), 6);
+    }
+}
@@ -1,15 +1,90 @@
 mod simple_grid;
 mod bus;
 
+use fehler::{throw, throws};
+use fnv::FnvHashMap;
+
 use crate::consts::Constants;
+use crate::error::{Error, Result};
 use crate::kirkmcdonald::ProductionGraph;
-use crate::pcb::{Pcb, NeededWires};
+use crate::pcb::{Pcb, NeededWires, Function, Direction, WireKind};
+use crate::recipe::{MachineKind, Prototypes};
 
 
 pub trait Placer {
-    fn place(pcb: &mut impl Pcb, tree: &ProductionGraph, consts: &Constants) -> NeededWires;
+    /// Build `graph` into `pcb`, with an output for each of its products
+    fn place(pcb: &mut impl Pcb, graph: &ProductionGraph, consts: &Constants, prototypes: &Prototypes) -> Result<Placement>;
+}
+
+/// What a [`Placer`] leaves for the router
+#[derive(Debug, Clone, Default)]
+pub struct Placement {
+    pub wires: NeededWires,
+    /// Edges (ingredient, consumer) of recipe cycles that are fed back against the build order
+    pub feedback: Vec<(String, String)>,
 }
 
 pub use simple_grid::SimpleGridPlacer;
 pub use bus::BusPlacer;
 
+/// The building that produces node `n` of `graph`, `None` for external inputs. Recipes the
+/// placers don't know how to build (fluid products, byproducts that would clog the belts, too
+/// many fluid ingredients) are an error rather than supplied from outside, add their products
+/// to [`Constants::external`] for that.
+#[throws]
+fn building_function(graph: &ProductionGraph, n: usize) -> Option<Function> {
+    let node = &graph.nodes[n];
+    let recipe = match node.recipe {
+        Some(ref recipe) => recipe,
+        None => return None,
+    };
+    let unbuildable = |why: String| Error::Placement(format!("can't build {}: {}", recipe, why));
+    let (machine, kind) = match (&node.machine, node.building) {
+        (Some(machine), Some(kind)) => (machine.clone(), kind),
+        _ => throw!(unbuildable("no machine crafts it".to_owned())),
+    };
+    let modules = node.modules.clone();
+    if node.output_kind != WireKind::Belt {
+        throw!(unbuildable(format!("{} is a fluid, which only leaves the placers as a product", node.output)));
+    }
+    if !node.byproducts.is_empty() {
+        throw!(unbuildable(format!("it also makes {}, which would clog the output belt", node.byproducts.join(", "))));
+    }
+    // our tiles are built around 3x3 machines
+    if machine.size != 3 {
+        throw!(unbuildable(format!("{} is {}x{}, our tiles only fit 3x3 machines", machine.name, machine.size, machine.size)));
+    }
+
+    let fluid_ports = machine.fluid_boxes.iter().filter(|b| b.input).count();
+    let (function, fluid_ports) = match kind {
+        MachineKind::Assembler => (Function::Assembler { recipe: recipe.clone(), machine, modules }, fluid_ports),
+        MachineKind::Furnace => (Function::Furnace { machine, modules }, fluid_ports),
+        // fluid inputs on the right, unused outputs on the left. The second input port would need a
+        // second pipe column where the output belt goes, so that one stays unused.
+        MachineKind::ChemicalLab => (Function::ChemicalPlant { recipe: recipe.clone(), machine, orientation: Direction::Right, modules }, fluid_ports.min(1)),
+        MachineKind::Centrifuge => (Function::Centrifuge { recipe: recipe.clone(), machine, modules }, fluid_ports),
+        // refineries only make fluids, which never get this far
+        MachineKind::OilRefinery | MachineKind::RocketSilo => throw!(unbuildable(format!("there's no layout for {}", machine.name))),
+    };
+
+    let mut fluids: Vec<_> = graph.inputs(n).filter(|x| x.kind != WireKind::Belt).map(|x| x.item.as_str()).collect();
+    fluids.dedup();
+    if fluids.len() > fluid_ports {
+        throw!(unbuildable(format!("it takes {}, but we only pipe {} fluid(s) into {}", fluids.join(", "), fluid_ports, machine.name)));
+    }
+    Some(function)
+}
+
+/// The buildings of the nodes of `graph`, `None` for its external inputs, and which of them
+/// makes each item
+#[throws]
+fn buildings(graph: &ProductionGraph) -> (Vec<Option<Function>>, FnvHashMap<&str, usize>) {
+    let functions = (0..graph.nodes.len()).map(|n| building_function(graph, n)).collect::<Result<Vec<_>>>()?;
+    let mut producers = FnvHashMap::default();
+    for (n, node) in graph.nodes.iter().enumerate().filter(|&(n, _)| functions[n].is_some()) {
+        if producers.insert(node.output.as_str(), n).is_some() {
+            throw!(Error::Placement(format!("several recipes make {}, but we can only build one of them", node.output)));
+        }
+    }
+    (functions, producers)
+}
@@ -1,220 +1,283 @@
 //! very simple and stupid grid placer
 
+use fehler::{throw, throws};
+
 use crate::consts::Constants;
+use crate::error::Error;
 use crate::{Entity, Direction, Function};
 use crate::kirkmcdonald::ProductionGraph;
-use crate::pcb::{Pcb, Point, Vector, NeededWires, need_belt, InserterKind};
-use crate::recipe::Category;
-use super::Placer;
+use crate::pcb::{Pcb, Point, Vector, NeededWires, need_belt, InserterKind, WireKind};
+use crate::recipe::Prototypes;
+use super::{Placer, Placement, buildings};
 
-use std::iter;
+use itertools::Itertools;
+use petgraph::prelude::*;
 
 pub struct SimpleGridPlacer;
 
 impl Placer for SimpleGridPlacer {
-    fn place(pcb: &mut impl Pcb, tree: &ProductionGraph, _: &Constants) -> NeededWires { simple_grid(pcb, tree) }
+    #[throws]
+    fn place(pcb: &mut impl Pcb, graph: &ProductionGraph, consts: &Constants, _prototypes: &Prototypes) -> Placement {
+        Placement { wires: simple_grid(pcb, graph, consts)?, feedback: vec![] }
+    }
 }
 
-fn simple_grid(pcb: &mut impl Pcb, tree: &ProductionGraph) -> NeededWires {
-    let needed_assemblers = needed_cells(&tree).count();
+#[throws]
+fn simple_grid(pcb: &mut impl Pcb, graph: &ProductionGraph, consts: &Constants) -> NeededWires {
+    if consts.beacons > 0 {
+        throw!(Error::Placement("the simple grid placer doesn't do beacons".to_owned()));
+    }
+    let belt = consts.max_belts;
+    let (functions, _) = buildings(graph)?;
+    // we don't lay any pipes
+    if let Some(n) = (0..graph.nodes.len()).find(|&n| functions[n].is_some() && graph.inputs(n).any(|x| x.kind != WireKind::Belt)) {
+        throw!(Error::Placement(format!("{} takes fluids, which the simple grid placer can't pipe", graph.nodes[n].output)));
+    }
+    let needed_assemblers: usize = (0..graph.nodes.len()).filter(|&n| functions[n].is_some()).map(|n| cells(graph, n)).sum();
 
     let gridsize = (needed_assemblers as f64).sqrt().ceil() as i32;
-    println!("gridsize={}", gridsize);
+
+    // producers go first, so their output belts are there when the consumers get placed
+    let mut deps = DiGraphMap::<usize, ()>::new();
+    for n in (0..graph.nodes.len()).filter(|&n| functions[n].is_some()) {
+        deps.add_node(n);
+        for item in ingredients(graph, n) {
+            if let Some(from) = source(graph, &functions, Some(n), item) {
+                deps.add_edge(from, n, ());
+            }
+        }
+    }
+    let order = petgraph::algo::toposort(&deps, None).map_err(|c| Error::Placement(format!(
+        "{} is part of a recipe cycle, which the simple grid placer can't build", graph.nodes[c.node_id()].output,
+    )))?;
+
+    // every consumer (and every product) gets its own belt off the end of the producer's output
+    let mut demand = vec![0; graph.nodes.len()];
+    for (from, _, _) in deps.all_edges() {
+        demand[from] += 1;
+    }
+    for (item, _) in &graph.products {
+        let from = source(graph, &functions, None, item).ok_or_else(|| Error::Placement(format!("don't know how to build {}", item)))?;
+        demand[from] += 1;
+    }
 
     let mut grid_i = 0;
     let mut needed_wires = NeededWires::new();
-    let (lins, lout) = gridrender_subtree(&tree, &mut grid_i, pcb, &mut needed_wires, gridsize).unwrap();
+    let mut outputs = vec![Vec::new(); graph.nodes.len()];
+    let mut lins = Vec::new();
+    for n in order {
+        let points = gridrender_node(graph, &functions, n, demand[n], &mut outputs, &mut lins, &mut grid_i, pcb, &mut needed_wires, gridsize, consts)?;
+        outputs[n] = points;
+    }
+    let louts: Vec<_> = graph.products.iter()
+        .map(|(item, _)| outputs[source(graph, &functions, None, item).unwrap()].pop().unwrap())
+        .collect();
 
     let gap_upper = 10;
-    pcb.add_all(&[
-        Entity { location: Point::new(0, -3 - gap_upper), function: Function::Belt(Direction::Up) },
-        Entity { location: Point::new(0, -4 - gap_upper), function: Function::Belt(Direction::Up) },
-    ]);
-    for i in 0..lins.len() {
+    for i in 0..louts.len() {
+        pcb.add_all(&[
+            Entity { location: Point::new(i as i32, -3 - gap_upper), function: Function::Belt(Direction::Up, belt) },
+            Entity { location: Point::new(i as i32, -4 - gap_upper), function: Function::Belt(Direction::Up, belt) },
+        ])?;
+    }
+    let inputs_x = louts.len() as i32;
+    for (i, (item, _)) in lins.iter().enumerate() {
+        pcb.add(Entity {
+            location: Point::new(i as i32 + inputs_x, -3 - gap_upper),
+            function: Function::Belt(Direction::Down, belt),
+        })?;
+        pcb.add(Entity {
+            location: Point::new(i as i32 + inputs_x, -4 - gap_upper),
+            function: Function::Belt(Direction::Down, belt),
+        })?;
+        // tells whoever connects the block what goes where
         pcb.add(Entity {
-            location: Point::new(i as i32 + 1, -3 - gap_upper),
-            function: Function::Belt(Direction::Down),
-        });
+            location: Point::new(i as i32 + inputs_x, -5 - gap_upper),
+            function: Function::InputMarker(item.to_owned()),
+        })?;
         pcb.add(Entity {
-            location: Point::new(i as i32 + 1, -4 - gap_upper),
-            function: Function::Belt(Direction::Down),
-        });
+            location: Point::new(i as i32 + inputs_x, -6 - gap_upper),
+            function: Function::Belt(Direction::Down, belt),
+        })?;
     }
-    needed_wires.push(need_belt(lout, Point::new(0, -3 - gap_upper)));
-    for (i, lin) in lins.into_iter().enumerate().rev() {
-        needed_wires.push(need_belt(Point::new(i as i32 + 1, -3 - gap_upper), lin));
+    for (i, lout) in louts.into_iter().enumerate() {
+        needed_wires.push(need_belt(lout, Point::new(i as i32, -3 - gap_upper), belt));
+    }
+    for (i, (_, lin)) in lins.into_iter().enumerate().rev() {
+        needed_wires.push(need_belt(Point::new(i as i32 + inputs_x, -3 - gap_upper), lin, belt));
     }
     needed_wires
 }
 
-fn needed_cells<'a>(g: &'a ProductionGraph) -> Box<dyn Iterator<Item = &'a str> + 'a> {
-    let upstream = g.inputs.iter().flat_map(needed_cells);
-    if g.building == Some(Category::Assembler) || g.building == Some(Category::Furnace) {
-        println!("i={}", g.inputs.len());
-        Box::new(iter::repeat(&g.output as &str).take(g.how_many.ceil().to_integer() as usize).chain(upstream))
-    } else {
-        Box::new(upstream)
-    }
+fn cells(graph: &ProductionGraph, n: usize) -> usize {
+    graph.nodes[n].how_many.ceil().to_integer() as usize
 }
 
-fn gridrender_subtree(
-    subtree: &ProductionGraph, grid_i: &mut i32, pcb: &mut impl Pcb,
-    needed_wires: &mut NeededWires, gridsize: i32,
-) -> Option<(Vec<Point>, Point)> {
-    if subtree.building == Some(Category::Assembler) || subtree.building == Some(Category::Furnace) {
-        let mut upper_inputs = Vec::new();
-        let mut our_inputs = Vec::new();
-
-        for input in &subtree.inputs {
-            match gridrender_subtree(input, grid_i, pcb, needed_wires, gridsize) {
-                None => {
-                    // becomes an input instead
-                    our_inputs.push(None);
-                },
-                Some((ui, out)) => {
-                    upper_inputs.extend(ui);
-                    our_inputs.push(Some(out));
-                },
-            }
-        }
+/// Distinct items flowing into node `n`, in the order of its recipe's ingredients
+fn ingredients(graph: &ProductionGraph, n: usize) -> Vec<&str> {
+    graph.inputs(n).map(|e| e.item.as_str()).unique().collect()
+}
 
-        assert_eq!(subtree.inputs.len(), our_inputs.len());
-        let second_input_belt = match subtree.inputs.len() {
-            1 | 2 => false,
-            3 | 4 => true,
-            _ => unreachable!(),
-        };
+/// The node we build that delivers `item` to node `to` (or out of the factory), `None` if it
+/// comes from outside
+fn source(graph: &ProductionGraph, functions: &[Option<Function>], to: Option<usize>, item: &str) -> Option<usize> {
+    graph.edges.iter().filter(|e| e.to == to && e.item == item).map(|e| e.from).find(|&from| functions[from].is_some())
+}
+
+/// Place the cells of node `n` and wire up its ingredients, returning its `splits` output points
+#[throws]
+#[allow(clippy::too_many_arguments)]
+fn gridrender_node<'a>(
+    graph: &'a ProductionGraph, functions: &[Option<Function>], n: usize, splits: usize,
+    outputs: &mut [Vec<Point>], lins: &mut Vec<(&'a str, Point)>, grid_i: &mut i32, pcb: &mut impl Pcb,
+    needed_wires: &mut NeededWires, gridsize: i32, consts: &Constants,
+) -> Vec<Point> {
+    let belt = consts.max_belts;
+    let node = &graph.nodes[n];
+    let main_function = functions[n].clone().unwrap();
+    let inputs = ingredients(graph, n);
+
+    let second_input_belt = match inputs.len() {
+        1 | 2 => false,
+        3 | 4 => true,
+        count => throw!(Error::Placement(format!("{} has {} ingredients, but at most 4 are supported", node.output, count))),
+    };
+    if splits > 4 {
+        throw!(Error::Placement(format!("{} goes to {} places, but at most 4 are supported", node.output, splits)));
+    }
 
-        let howmany = subtree.how_many.ceil().to_integer() as usize;
-        let mut prev = None;
-        for _ in 0..howmany {
-            let i = *grid_i;
-            let grid_x = i % gridsize;
-            let grid_y = i / gridsize;
+    let howmany = cells(graph, n);
+    let mut prev = None;
+    for _ in 0..howmany {
+        let i = *grid_i;
+        let grid_x = i % gridsize;
+        let grid_y = i / gridsize;
 
-            let cell_size_x = 15;
-            let cell_size_y = 9;
+        let cell_size_x = 15;
+        let cell_size_y = 9;
+
+        let start = Point::new(cell_size_x * grid_x, cell_size_y * grid_y);
+
+        pcb.add_all(&[
+            Entity {
+                location: start + Vector::new(2, 0),
+                function: main_function.clone(),
+            },
+            // output belt
+            Entity { location: start + Vector::new(0, 0), function: Function::Belt(Direction::Down, belt) },
+            Entity { location: start + Vector::new(0, 1), function: Function::Belt(Direction::Down, belt) },
+            Entity { location: start + Vector::new(0, 2), function: Function::Belt(Direction::Down, belt) },
+            Entity {
+                location: start + Vector::new(1, 1),
+                function: Function::Inserter {
+                    orientation: Direction::Left,
+                    kind: InserterKind::Normal,
+                },
+            },
+            // input belt
+            Entity { location: start + Vector::new(6, 0), function: Function::Belt(Direction::Left, belt) },
+            Entity { location: start + Vector::new(6, 1), function: Function::Belt(Direction::Up, belt) },
+            Entity { location: start + Vector::new(6, 2), function: Function::Belt(Direction::Up, belt) },
+            Entity {
+                location: start + Vector::new(5, 0),
+                function: Function::Inserter {
+                    orientation: Direction::Left,
+                    kind: InserterKind::Normal,
+                },
+            },
+            Entity { location: start + Vector::new(3, 3), function: Function::ElectricPole },
+        ])?;
 
-            let start = Point::new(cell_size_x * grid_x, cell_size_y * grid_y);
+        if (grid_y == 0) && (grid_x != (gridsize - 1)) {
+            pcb.add(Entity { location: start + Vector::new(10, 1), function: Function::ElectricPole })?;
+        }
 
-            let main_function = match subtree.building {
-                Some(Category::Assembler) => Function::Assembler { recipe: subtree.output.clone() },
-                Some(Category::Furnace) => Function::Furnace,
-                _ => unreachable!(),
-            };
+        if let Some(prev) = prev {
+            needed_wires.push(need_belt(prev + Vector::new(0, 2), start + Vector::new(0, 0), belt));
+            needed_wires.push(need_belt(start + Vector::new(6, 0), prev + Vector::new(6, 2), belt));
+        }
 
+        if second_input_belt {
             pcb.add_all(&[
+                // input belt 2
                 Entity {
-                    location: start + Vector::new(2, 0),
-                    function: main_function,
+                    location: start + Vector::new(7, 0),
+                    function: Function::Belt(Direction::Down, belt),
                 },
-                // output belt
-                Entity { location: start + Vector::new(0, 0), function: Function::Belt(Direction::Down) },
-                Entity { location: start + Vector::new(0, 1), function: Function::Belt(Direction::Down) },
-                Entity { location: start + Vector::new(0, 2), function: Function::Belt(Direction::Down) },
                 Entity {
-                    location: start + Vector::new(1, 1),
-                    function: Function::Inserter {
-                        orientation: Direction::Left,
-                        kind: InserterKind::Normal,
-                    },
+                    location: start + Vector::new(7, 1),
+                    function: Function::Belt(Direction::Up, belt),
+                },
+                Entity {
+                    location: start + Vector::new(7, 2),
+                    function: Function::Belt(Direction::Up, belt),
                 },
-                // input belt
-                Entity { location: start + Vector::new(6, 0), function: Function::Belt(Direction::Left) },
-                Entity { location: start + Vector::new(6, 1), function: Function::Belt(Direction::Up) },
-                Entity { location: start + Vector::new(6, 2), function: Function::Belt(Direction::Up) },
                 Entity {
-                    location: start + Vector::new(5, 0),
+                    location: start + Vector::new(5, 1),
                     function: Function::Inserter {
                         orientation: Direction::Left,
-                        kind: InserterKind::Normal,
+                        kind: InserterKind::LongHanded,
                     },
                 },
-                Entity { location: start + Vector::new(3, 3), function: Function::ElectricPole },
-            ]);
-
-            if (grid_y == 0) && (grid_x != (gridsize - 1)) {
-                pcb.add(Entity { location: start + Vector::new(10, 1), function: Function::ElectricPole });
-            }
-
+            ])?;
             if let Some(prev) = prev {
-                needed_wires.push(need_belt(prev + Vector::new(0, 2), start + Vector::new(0, 0)));
-                needed_wires.push(need_belt(start + Vector::new(6, 0), prev + Vector::new(6, 2)));
+                needed_wires.push(need_belt(start + Vector::new(7, 0), prev + Vector::new(7, 2), belt));
             }
+        }
 
-            if second_input_belt {
-                pcb.add_all(&[
-                    // input belt 2
-                    Entity {
-                        location: start + Vector::new(7, 0),
-                        function: Function::Belt(Direction::Down),
-                    },
-                    Entity {
-                        location: start + Vector::new(7, 1),
-                        function: Function::Belt(Direction::Up),
-                    },
-                    Entity {
-                        location: start + Vector::new(7, 2),
-                        function: Function::Belt(Direction::Up),
-                    },
-                    Entity {
-                        location: start + Vector::new(5, 1),
-                        function: Function::Inserter {
-                            orientation: Direction::Left,
-                            kind: InserterKind::LongHanded,
-                        },
-                    },
-                ]);
-                if let Some(prev) = prev {
-                    needed_wires.push(need_belt(start + Vector::new(7, 0), prev + Vector::new(7, 2)));
-                }
-            }
+        prev = Some(start);
+        *grid_i += 1;
+    }
 
-            prev = Some(start);
-            *grid_i += 1;
-        }
+    let prev = prev.unwrap();
+    // a chain of splitters below the last cell when several consumers share the output
+    let mut my_outputs = Vec::new();
+    for i in 0..splits.saturating_sub(1) as i32 {
+        pcb.add_all(&[
+            Entity { location: prev + Vector::new(0, 3 + 2 * i), function: Function::Splitter(Direction::Down, belt) },
+            Entity { location: prev + Vector::new(0, 4 + 2 * i), function: Function::Belt(Direction::Down, belt) },
+            Entity { location: prev + Vector::new(1, 4 + 2 * i), function: Function::Belt(Direction::Down, belt) },
+        ])?;
+        my_outputs.push(prev + Vector::new(1, 4 + 2 * i));
+    }
+    my_outputs.push(prev + Vector::new(0, 2 * splits.max(1) as i32));
 
-        let prev = prev.unwrap();
-        let my_output = prev + Vector::new(0, 2);
-        // connect intra here
-        let mut target_points = Vec::new();
-        if our_inputs.len() == 1 {
-            // single input, so no lane organization needed
-            target_points.push(prev + Vector::new(6, 2));
-        } else {
-            pcb.add_all(&[
-                Entity { location: prev + Vector::new(6, 3), function: Function::Belt(Direction::Up) },
-                Entity { location: prev + Vector::new(5, 3), function: Function::Belt(Direction::Right) },
-                Entity { location: prev + Vector::new(7, 3), function: Function::Belt(Direction::Left) },
-            ]);
-            target_points.push(prev + Vector::new(5, 3));
-            target_points.push(prev + Vector::new(7, 3));
-
-            if second_input_belt {
-                if our_inputs.len() == 3 {
-                    target_points.push(prev + Vector::new(7, 2));
-                } else {
-                    pcb.add_all(&[
-                        Entity { location: prev + Vector::new(8, 2), function: Function::Belt(Direction::Left) },
-                        Entity { location: prev + Vector::new(8, 1), function: Function::Belt(Direction::Down) },
-                        Entity { location: prev + Vector::new(8, 3), function: Function::Belt(Direction::Up) },
-                    ]);
-                    target_points.push(prev + Vector::new(8, 2));
-                    target_points.push(prev + Vector::new(8, 3));
-                }
-            }
-        }
+    // connect intra here
+    let mut target_points = Vec::new();
+    if inputs.len() == 1 {
+        // single input, so no lane organization needed
+        target_points.push(prev + Vector::new(6, 2));
+    } else {
+        pcb.add_all(&[
+            Entity { location: prev + Vector::new(6, 3), function: Function::Belt(Direction::Up, belt) },
+            Entity { location: prev + Vector::new(5, 3), function: Function::Belt(Direction::Right, belt) },
+            Entity { location: prev + Vector::new(7, 3), function: Function::Belt(Direction::Left, belt) },
+        ])?;
+        target_points.push(prev + Vector::new(5, 3));
+        target_points.push(prev + Vector::new(7, 3));
 
-        assert_eq!(our_inputs.len(), target_points.len());
-        for (from, to) in our_inputs.into_iter().zip(target_points) {
-            match from {
-                None => upper_inputs.push(to),
-                Some(from) => needed_wires.push(need_belt(from, to)),
+        if second_input_belt {
+            if inputs.len() == 3 {
+                target_points.push(prev + Vector::new(7, 2));
+            } else {
+                pcb.add_all(&[
+                    Entity { location: prev + Vector::new(8, 2), function: Function::Belt(Direction::Left, belt) },
+                    Entity { location: prev + Vector::new(8, 1), function: Function::Belt(Direction::Down, belt) },
+                    Entity { location: prev + Vector::new(8, 3), function: Function::Belt(Direction::Up, belt) },
+                ])?;
+                target_points.push(prev + Vector::new(8, 2));
+                target_points.push(prev + Vector::new(8, 3));
             }
         }
+    }
 
-        Some((upper_inputs, my_output))
-    } else {
-        None
+    assert_eq!(inputs.len(), target_points.len());
+    for (item, to) in inputs.into_iter().zip(target_points) {
+        match source(graph, functions, Some(n), item) {
+            None => lins.push((item, to)),
+            Some(from) => needed_wires.push(need_belt(outputs[from].pop().unwrap(), to, belt)),
+        }
     }
+
+    my_outputs
 }
@@ -0,0 +1,111 @@
+//! Rates at which every machine of a factory runs all the time
+
+use std::cmp::max;
+
+use fehler::throws;
+
+use crate::Rational;
+use crate::consts::Constants;
+use crate::error::Error;
+use crate::kirkmcdonald::{kirkmcdonald, ProductionGraph, ProductionNode};
+use crate::pcb::WireKind;
+use crate::recipe::{Prototypes, Recipe};
+
+/// How big the factory may get
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Machines(i32),
+    /// Tiles covered by machines
+    Area(i32),
+}
+
+/// A rate of the product and the machines it takes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ratio {
+    pub per_second: Rational,
+    /// Machines per recipe, all whole if `idle` is zero
+    pub machines: Vec<(String, Rational)>,
+    /// Whole machines to build
+    pub total: i32,
+    /// Most machine time any recipe leaves unused
+    pub idle: Rational,
+}
+
+/// Rates of `target` within `limit`, slowest first, at which no recipe leaves more than
+/// `tolerance` of a machine idle. With a tolerance of zero these are the exact ratios.
+#[throws]
+pub fn whole_machine_rates(
+    recipes: &[Recipe], prototypes: &Prototypes, target: &str, limit: Limit, tolerance: Rational, consts: &Constants,
+) -> Vec<Ratio> {
+    let unit = kirkmcdonald(recipes, prototypes, &[(target.to_owned(), Rational::from(1))], &WireKind::Belt, consts)?;
+    ratios(&unit.graph, limit, tolerance)
+}
+
+/// Like [`whole_machine_rates`], for a `graph` making one of its product per second
+fn ratios(graph: &ProductionGraph, limit: Limit, tolerance: Rational) -> Vec<Ratio> {
+    let nodes: Vec<&ProductionNode> = graph.nodes.iter().filter(|n| n.recipe.is_some() && n.how_many > Rational::from(0)).collect();
+    let budget = match limit {
+        Limit::Machines(budget) | Limit::Area(budget) => budget,
+    };
+    let cost = |n: &ProductionNode| match limit {
+        Limit::Machines(_) => 1,
+        Limit::Area(_) => n.machine.as_ref().map_or(1, |m| m.size * m.size),
+    };
+    let whole = |n: &ProductionNode, rate: Rational| (rate * n.how_many).ceil().to_integer();
+    let size = |rate: Rational| nodes.iter().map(|n| whole(n, rate) * cost(n)).sum::<i32>();
+
+    // idle machine time only shrinks as the rate goes up, until some recipe needs another machine,
+    // so the best rates are those where one of them runs on whole machines
+    let mut rates = Vec::new();
+    for node in &nodes {
+        for k in 1.. {
+            let rate = Rational::from(k) / node.how_many;
+            if size(rate) > budget {
+                break;
+            }
+            rates.push(rate);
+        }
+    }
+    rates.sort();
+    rates.dedup();
+
+    rates.into_iter().filter_map(|rate| {
+        let machines: Vec<_> = nodes.iter().map(|n| (n.recipe.clone().unwrap(), rate * n.how_many)).collect();
+        let idle = machines.iter().map(|(_, x)| x.ceil() - x).fold(Rational::from(0), max);
+        let total = nodes.iter().map(|n| whole(n, rate)).sum();
+        (idle <= tolerance).then_some(Ratio { per_second: rate, machines, total, idle })
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use crate::recipe::test_util::{item, machine, recipe};
+
+    #[test]
+    fn green_circuits() {
+        let recipes = vec![
+            recipe(vec![item("copper-plate", 1)], vec![item("copper-cable", 2)]),
+            recipe(vec![item("iron-plate", 1), item("copper-cable", 3)], vec![item("electronic-circuit", 1)]),
+        ];
+        let mut prototypes = Prototypes::default();
+        prototypes.machines.insert("assembling-machine-1".to_owned(), Arc::new(machine("assembling-machine-1", &["crafting"])));
+        let consts = Constants::default();
+        let rates = |limit, tolerance| {
+            whole_machine_rates(&recipes, &prototypes, "electronic-circuit", limit, tolerance, &consts).unwrap()
+                .into_iter().map(|r| r.per_second).collect::<Vec<_>>()
+        };
+
+        // the classic 3 cable machines for 2 circuit machines
+        let exact = whole_machine_rates(&recipes, &prototypes, "electronic-circuit", Limit::Machines(10), Rational::from(0), &consts).unwrap();
+        assert_eq!(exact.iter().map(|r| r.per_second).collect::<Vec<_>>(), [Rational::from(4), Rational::from(8)]);
+        let machines: Vec<_> = exact[0].machines.iter().map(|(name, x)| (name.as_str(), *x)).collect();
+        assert_eq!(machines, [("electronic-circuit", Rational::from(2)), ("copper-cable", Rational::from(3))]);
+        assert_eq!((exact[0].total, exact[0].idle), (5, Rational::from(0)));
+
+        assert_eq!(rates(Limit::Area(45), Rational::from(0)), [Rational::from(4)]);
+        assert!(rates(Limit::Machines(4), Rational::from(0)).is_empty());
+        assert_eq!(rates(Limit::Machines(5), Rational::new(1, 2)), [Rational::from(2), Rational::from(4)]);
+    }
+}
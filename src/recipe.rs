@@ -1,23 +1,80 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use rlua::{Lua, Result, Table};
+use fehler::{throw, throws};
+use fnv::{FnvHashMap, FnvHashSet};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
+use crate::consts::BeltType;
+use crate::data::{self, RawData};
+use crate::lp;
+use crate::error::Error;
+use crate::overrides::Compound;
 use crate::Rational;
-use crate::pcb::WireKind;
+use crate::pcb::{InserterKind, WireKind};
 
-#[derive(Debug, Clone)]
+/// Prototype types that are items of some sort
+pub const ITEM_TYPES: [&str; 10] = ["item", "tool", "module", "capsule", "ammo", "gun", "armor", "repair-tool", "item-with-entity-data", "rail-planner"];
+/// Prototype types that craft recipes
+pub const MACHINE_TYPES: [&str; 3] = ["assembling-machine", "furnace", "rocket-silo"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Recipe {
+    pub name: String,
     pub ingredients: ItemSpec,
     pub results: ItemSpec,
-    pub category: Category,
+    /// Crafting category, i.e. the machines that can make it
+    pub category: String,
     pub crafting_time: f64,
+    pub allow_productivity: bool,
+    /// Available without research
+    pub enabled: bool,
+    pub compound: Option<Compound>,
+    /// Both variants of recipes that differ by difficulty, indexed by [`Difficulty`]; the fields
+    /// above are those of the selected one, normal unless [`Recipe::select`] picked another
+    pub variants: Option<Box<[Variant; 2]>>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum Category {
-    Assembler,
+/// What a recipe takes and makes at one difficulty
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Variant {
+    pub ingredients: ItemSpec,
+    pub results: ItemSpec,
+    pub crafting_time: f64,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Difficulty {
+    #[default]
+    Normal,
+    /// Marathon recipes
+    Expensive,
+}
+
+impl Recipe {
+    /// Switch to the variant of `difficulty`. Overridden recipes can't switch, as their merged
+    /// ingredients belong to one variant; select first and apply the overrides afterwards.
+    #[throws]
+    pub fn select(&mut self, difficulty: Difficulty) {
+        if let Some(ref variants) = self.variants {
+            if self.compound.is_some() {
+                throw!(Error::Override(format!("{} is already overridden, select the difficulty first", self.name)));
+            }
+            let Variant { ingredients, results, crafting_time, enabled } = variants[difficulty as usize].clone();
+            self.ingredients = ingredients;
+            self.results = results;
+            self.crafting_time = crafting_time;
+            self.enabled = enabled;
+        }
+    }
+}
 
-    // not yet implemented
+/// What the placers have to build for a machine, going by the vanilla categories it crafts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MachineKind {
+    Assembler,
     Centrifuge,
     ChemicalLab,
     OilRefinery,
@@ -26,119 +83,967 @@ pub enum Category {
 }
 
 pub type ItemSpec = Vec<Ingredient>;
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Ingredient {
     pub name: String,
+    /// Expected amount per craft, i.e. including probability and ranges
     pub amount: Rational,
     pub kind: WireKind,
+    /// Part of `amount` that productivity doesn't apply to
+    pub catalyst: Rational,
+    pub temperature: Option<Temperature>,
 }
 
-pub fn extract_recipes(path: impl AsRef<Path>) -> Result<Vec<Recipe>> {
-    let lua = Lua::new();
-    lua.context(|c| {
-        c.load(
-            r#"
-Importer = {}
-Importer.__index = Importer
-
-function Importer:create()
-   local i = {}
-   setmetatable(i,Importer)
-   i.inner = {}
-   return i
-end
-
-function Importer:extend(more)
-   for _, v in ipairs(more) do
-       table.insert(self.inner, v)
-   end
-end
-
-data = Importer:create()
-"#,
-        )
-            .exec()?;
-        Ok(())
-    })?;
-
-    for file in std::fs::read_dir(path).unwrap() {
-        let file = file.unwrap();
-        match file.path().extension() {
-            Some(x) if x == "lua" => {
-                println!("parsing {:?}", file.path());
-                let code = std::fs::read_to_string(file.path()).unwrap();
-                lua.context(|c| c.load(&code).exec())?;
-            },
-            _ => (),
+impl Ingredient {
+    pub fn new(name: impl Into<String>, amount: Rational, kind: WireKind) -> Self {
+        Ingredient { name: name.into(), amount, kind, catalyst: Rational::from(0), temperature: None }
+    }
+
+    /// Expected amount per craft with `productivity`
+    pub fn with_productivity(&self, productivity: Rational) -> Rational {
+        self.amount + (self.amount - self.catalyst) * productivity
+    }
+}
+
+/// Temperature of a fluid: results have an exact one, ingredients may accept a range
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Temperature {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Temperature {
+    pub fn contains(&self, other: &Temperature) -> bool {
+        self.min <= other.min && other.max <= self.max
+    }
+
+    pub fn intersect(&self, other: &Temperature) -> Temperature {
+        Temperature { min: self.min.max(other.min), max: self.max.min(other.max) }
+    }
+}
+
+/// Recipes plus everything else we know about the game
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Database {
+    pub recipes: Vec<Recipe>,
+    pub prototypes: Prototypes,
+}
+
+/// Which technologies are researched
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Research {
+    /// Everything, and every recipe is available even if nothing unlocks it
+    #[default]
+    All,
+    /// Exactly these
+    Only(Vec<String>),
+    /// This one and everything it requires
+    UpTo(String),
+}
+
+impl Database {
+    /// The technologies `research` covers, `None` for [`Research::All`]
+    #[throws]
+    pub fn researched(&self, research: &Research) -> Option<Vec<Technology>> {
+        let technology = |name: &str| self.prototypes.technologies.get(name).ok_or_else(|| Error::MissingPrototype(name.to_owned()));
+        match research {
+            Research::All => None,
+            Research::Only(names) => Some(names.iter().map(|n| technology(n).cloned()).collect::<Result<_, _>>()?),
+            Research::UpTo(name) => {
+                let mut seen = FnvHashSet::default();
+                let mut todo = vec![name.as_str()];
+                let mut researched = Vec::new();
+                while let Some(name) = todo.pop() {
+                    if seen.insert(name) {
+                        let tech = technology(name)?;
+                        todo.extend(tech.prerequisites.iter().map(String::as_str));
+                        researched.push(tech.clone());
+                    }
+                }
+                Some(researched)
+            }
+        }
+    }
+
+    /// Switch every recipe to its variant of `difficulty`, before any overrides are applied
+    #[throws]
+    pub fn select(&mut self, difficulty: Difficulty) {
+        // overrides merge recipes into others, which would keep the ingredients of the old variant
+        if let Some(recipe) = self.recipes.iter().find(|r| r.compound.is_some()) {
+            throw!(Error::Override(format!("{} is already overridden, select the difficulty first", recipe.name)));
+        }
+        for recipe in &mut self.recipes {
+            recipe.select(difficulty)?;
         }
     }
 
+    /// Drop the recipes that are neither enabled from the start nor unlocked by `technologies`
+    pub fn retain_unlocked(&mut self, technologies: &[Technology]) {
+        let unlocked: FnvHashSet<&str> = technologies.iter().flat_map(|t| &t.unlocks).map(String::as_str).collect();
+        self.recipes.retain(|r| r.enabled || unlocked.contains(r.name.as_str()));
+    }
+}
+
+/// The prototypes the solver, placers and renderer look at, by name
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Prototypes {
+    pub items: FnvHashMap<String, Item>,
+    pub fluids: FnvHashMap<String, Fluid>,
+    pub machines: FnvHashMap<String, Arc<Machine>>,
+    /// by the name of the transport belt of each tier
+    pub belts: FnvHashMap<String, Belt>,
+    pub inserters: FnvHashMap<String, Inserter>,
+    pub beacons: FnvHashMap<String, Arc<Beacon>>,
+    pub modules: FnvHashMap<String, Arc<Module>>,
+    pub poles: FnvHashMap<String, Pole>,
+    pub pipes: FnvHashMap<String, Pipe>,
+    pub technologies: FnvHashMap<String, Technology>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Item {
+    pub name: String,
+    pub stack_size: u32,
+    pub subgroup: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fluid {
+    pub name: String,
+    pub default_temperature: f64,
+    pub subgroup: Option<String>,
+}
+
+/// Anything that crafts recipes: assembling machines, furnaces, chemical plants, ...
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Machine {
+    pub name: String,
+    pub kind: MachineKind,
+    /// Burner machines need fuel, we'd rather not
+    pub electric: bool,
+    pub crafting_speed: Rational,
+    /// Recipe categories it can craft
+    pub categories: Vec<String>,
+    pub module_slots: usize,
+    /// In prototype order
+    pub fluid_boxes: Vec<FluidBox>,
+    /// Side length in tiles, they're all square
+    pub size: i32,
+    /// kW while crafting, electric or fuel
+    pub energy_usage: Rational,
+    /// kW electric machines draw even when idle, on top of `energy_usage`
+    pub drain: Rational,
+    /// Per minute while crafting
+    pub pollution: Rational,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FluidBox {
+    pub input: bool,
+    /// Tile the pipe connects to, relative to the top left corner of the machine facing up
+    pub position: (i32, i32),
+}
+
+/// Transport belt, underground belt and splitter of the same speed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Belt {
+    pub name: String,
+    pub lane_items_per_second: Rational,
+    pub underground: Option<String>,
+    pub splitter: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Inserter {
+    pub name: String,
+    /// Revolutions per tick
+    pub rotation_speed: Rational,
+    /// Stack inserters get a different capacity bonus
+    pub stack: bool,
+    /// kW while swinging
+    pub energy_usage: Rational,
+    /// kW even when idle, on top of `energy_usage`
+    pub drain: Rational,
+    /// Per minute while swinging
+    pub pollution: Rational,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Beacon {
+    pub name: String,
+    /// kW, whether the machines around it craft or not
+    pub energy_usage: Rational,
+    /// Per minute
+    pub pollution: Rational,
+}
+
+/// What a module adds to the machine or beacon it's in, e.g. 1/2 for +50%
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Module {
+    pub name: String,
+    pub speed: Rational,
+    /// Extra power draw
+    pub consumption: Rational,
+    /// Extra pollution on top of the extra power draw
+    pub pollution: Rational,
+    pub productivity: Rational,
+}
+
+impl Module {
+    /// Extra results of a machine with `modules`, e.g. 1/10 for +10%
+    pub fn total_productivity(modules: &[Arc<Module>]) -> Rational {
+        modules.iter().map(|m| m.productivity).sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pole {
+    pub name: String,
+    /// Tiles it powers in every direction from its center
+    pub supply_area_distance: Rational,
+    /// Longest wire to the next pole, in tiles
+    pub wire_reach: Rational,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pipe {
+    pub name: String,
+    /// The pipe-to-ground that goes with it
+    pub underground: Option<String>,
+}
+
+/// A technology and what researching it does for us
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Technology {
+    pub name: String,
+    pub prerequisites: Vec<String>,
+    /// Recipes it enables
+    pub unlocks: Vec<String>,
+    /// Extra items per swing for inserters other than stack inserters
+    pub inserter_stack_size_bonus: i32,
+    pub stack_inserter_capacity_bonus: i32,
+    pub mining_productivity: Rational,
+}
+
+impl Prototypes {
+    #[throws]
+    pub fn machine(&self, name: &str) -> &Arc<Machine> {
+        self.machines.get(name).ok_or_else(|| Error::MissingPrototype(name.to_owned()))?
+    }
+
+    #[throws]
+    pub fn belt(&self, tier: BeltType) -> &Belt {
+        self.belts.get(tier.belt_name()).ok_or_else(|| Error::MissingPrototype(tier.belt_name().to_owned()))?
+    }
+
+    #[throws]
+    pub fn inserter(&self, kind: InserterKind) -> &Inserter {
+        self.inserters.get(kind.name()).ok_or_else(|| Error::MissingPrototype(kind.name().to_owned()))?
+    }
+
+    #[throws]
+    pub fn beacon(&self, name: &str) -> &Arc<Beacon> {
+        self.beacons.get(name).ok_or_else(|| Error::MissingPrototype(name.to_owned()))?
+    }
+
+    #[throws]
+    pub fn module(&self, name: &str) -> &Arc<Module> {
+        self.modules.get(name).ok_or_else(|| Error::MissingPrototype(name.to_owned()))?
+    }
+
+    #[throws]
+    pub fn pole(&self, name: &str) -> &Pole {
+        self.poles.get(name).ok_or_else(|| Error::MissingPrototype(name.to_owned()))?
+    }
+
+    #[throws]
+    pub fn pipe(&self, name: &str) -> &Pipe {
+        self.pipes.get(name).ok_or_else(|| Error::MissingPrototype(name.to_owned()))?
+    }
+
+    /// Machines that can craft recipes of `category`
+    pub fn machines_for<'a>(&'a self, category: &'a str) -> impl Iterator<Item = &'a Arc<Machine>> + 'a {
+        self.machines.values().filter(move |m| m.categories.iter().any(|c| c == category))
+    }
+
+    /// Items per stack, or 1 for fluids and things we don't know
+    pub fn stack_size(&self, item: &str) -> u32 {
+        self.items.get(item).map(|i| i.stack_size).unwrap_or(1)
+    }
+}
+
+/// Collect the recipes, sorted by name, and the other prototypes from either a
+/// `data-raw-dump.json` at `path` or by running the data stage of the game data (or loose
+/// prototype files) at `path` plus `mods`. The recipes have both difficulties, normal selected.
+#[throws]
+pub fn extract_database(path: impl AsRef<Path>, mods: &[PathBuf]) -> Database {
+    let path = path.as_ref();
+    let raw = if path.is_file() && path.extension().is_some_and(|e| e == "json") {
+        if !mods.is_empty() {
+            throw!(Error::DataStage("mods are already part of a data-raw dump".to_owned()));
+        }
+        data::load_dump(path)?
+    } else {
+        let mut paths = vec![path.to_owned()];
+        paths.extend_from_slice(mods);
+        data::raw_data(&data::load(&paths)?)?
+    };
+    Database { recipes: recipes_from_raw(&raw)?, prototypes: prototypes_from_raw(&raw)? }
+}
+
+fn prototypes<'a>(raw: &'a RawData, ty: &str) -> impl Iterator<Item = &'a Value> {
+    raw.get(ty).and_then(Value::as_object).into_iter().flat_map(|m| m.values())
+}
+
+#[throws]
+pub fn recipes_from_raw(raw: &RawData) -> Vec<Recipe> {
+    let prototypes = |ty: &str| prototypes(raw, ty);
+    let fluids: FnvHashSet<&str> = prototypes("fluid").filter_map(|f| f["name"].as_str()).collect();
+
     let mut recipes = Vec::new();
+    let mut productivity_limitation = Vec::new();
 
-    lua.context::<_, Result<()>>(|c| {
-        let data: Table = c.globals().get("data")?;
-        let inner: Table = data.get("inner")?;
-        for item in inner.sequence_values::<Table>() {
-            let item = item?;
-            if item.get::<_, String>("type")? == "recipe" {
-                let cat: String = item.get("category").unwrap_or("crafting".to_owned());
-                // ignore expensive mode
-                let item = if item.contains_key("expensive")? { item.get("normal")? } else { item };
-
-                let ingredients = normalize_item_spec(item.get("ingredients")?)?;
-                let crafting_time = item.get("energy_required").unwrap_or(0.5);
-                let results = match item.get("result") {
-                    Ok(r) => vec![Ingredient { name: r, amount: Rational::from(item.get("result_count").unwrap_or(1)), kind: WireKind::Belt }],
-                    _ => normalize_item_spec(item.get("results")?)?,
-                };
-
-                let category = if cat == "crafting"
-                    || cat == "advanced-crafting"
-                    || cat == "crafting-with-fluid"
-                {
-                    Category::Assembler
-                } else if cat == "centrifuging" {
-                    Category::Centrifuge
-                } else if cat == "chemistry" {
-                    Category::ChemicalLab
-                } else if cat == "oil-processing" {
-                    Category::OilRefinery
-                } else if cat == "rocket-building" {
-                    Category::RocketSilo
-                } else if cat == "smelting" {
-                    Category::Furnace
-                } else {
-                    unimplemented!();
-                };
-
-                recipes.push(Recipe { results, crafting_time, category, ingredients });
-            }
+    for item in prototypes("module") {
+        if item["category"] == "productivity" {
+            productivity_limitation.extend(list(&item["limitation"]).filter_map(Value::as_str));
+        }
+    }
+    for item in prototypes("recipe") {
+        let name = string(item, "name")?;
+        let category = item["category"].as_str().unwrap_or("crafting").to_owned();
+        // either difficulty may be missing or false, meaning it's the same as the other one
+        let (normal, expensive) = (&item["normal"], &item["expensive"]);
+        let variants = match (normal.is_object(), expensive.is_object()) {
+            (false, false) => None,
+            (true, false) => Some([normal, normal]),
+            (false, true) => Some([expensive, expensive]),
+            (true, true) => Some([normal, expensive]),
+        };
+        let variants = match variants {
+            Some([normal, expensive]) => Some(Box::new([variant(normal, item, &fluids)?, variant(expensive, item, &fluids)?])),
+            None => None,
+        };
+        let Variant { ingredients, results, crafting_time, enabled } = match variants {
+            Some(ref variants) => variants[Difficulty::Normal as usize].clone(),
+            None => variant(item, item, &fluids)?,
+        };
+
+        recipes.push(Recipe { name, results, crafting_time, category, ingredients, allow_productivity: true, enabled, compound: None, variants });
+    }
+    // data.raw has no order
+    recipes.sort_by(|a, b| a.name.cmp(&b.name));
+
+    // without the module prototypes we can't tell, so trust whoever configured the modules
+    if !productivity_limitation.is_empty() {
+        for recipe in &mut recipes {
+            recipe.allow_productivity = productivity_limitation.contains(&recipe.name.as_str());
         }
+    }
+
+    recipes
+}
 
-        Ok(())
-    })?;
+#[throws]
+pub fn prototypes_from_raw(raw: &RawData) -> Prototypes {
+    let mut db = Prototypes::default();
+    for prototype in ITEM_TYPES.iter().flat_map(|ty| prototypes(raw, ty)) {
+        let name = string(prototype, "name")?;
+        let stack_size = prototype["stack_size"].as_u64().unwrap_or(1) as u32;
+        let subgroup = prototype["subgroup"].as_str().map(str::to_owned);
+        db.items.insert(name.clone(), Item { name, stack_size, subgroup });
+    }
+    for prototype in prototypes(raw, "fluid") {
+        let name = string(prototype, "name")?;
+        let default_temperature = prototype["default_temperature"].as_f64().unwrap_or(15.);
+        let subgroup = prototype["subgroup"].as_str().map(str::to_owned);
+        db.fluids.insert(name.clone(), Fluid { name, default_temperature, subgroup });
+    }
+    for prototype in MACHINE_TYPES.iter().flat_map(|ty| prototypes(raw, ty)) {
+        let machine = machine(prototype)?;
+        db.machines.insert(machine.name.clone(), Arc::new(machine));
+    }
 
-    Ok(recipes)
+    // the three entities of a belt tier only have their speed in common
+    let same_speed = |ty: &str, speed: &Value| prototypes(raw, ty).find(|p| p["speed"].as_f64() == speed.as_f64());
+    for prototype in prototypes(raw, "transport-belt") {
+        let name = string(prototype, "name")?;
+        // items per tick and tile, with 4 items per lane and tile
+        let lane_items_per_second = rational(&prototype["speed"])? * 60 * 4;
+        let underground = match prototype["related_underground_belt"].as_str() {
+            Some(u) => prototypes(raw, "underground-belt").find(|p| p["name"] == u),
+            None => same_speed("underground-belt", &prototype["speed"]),
+        };
+        let underground = underground.and_then(|u| u["name"].as_str()).map(str::to_owned);
+        let splitter = same_speed("splitter", &prototype["speed"]).and_then(|s| s["name"].as_str()).map(str::to_owned);
+        db.belts.insert(name.clone(), Belt { name, lane_items_per_second, underground, splitter });
+    }
+    for prototype in prototypes(raw, "inserter") {
+        let name = string(prototype, "name")?;
+        let rotation_speed = rational(&prototype["rotation_speed"])?;
+        let stack = prototype["stack"].as_bool().unwrap_or(false);
+        // energy per full rotation and extension, times the share of them done per tick
+        let per_tick = kilo(&prototype["energy_per_rotation"], 'J')? * rotation_speed
+            + kilo(&prototype["energy_per_movement"], 'J')? * optional_rational(&prototype["extension_speed"])?;
+        let energy_usage = per_tick * 60;
+        let drain = kilo(&prototype["energy_source"]["drain"], 'W')?;
+        let pollution = emissions(prototype, energy_usage)?;
+        db.inserters.insert(name.clone(), Inserter { name, rotation_speed, stack, energy_usage, drain, pollution });
+    }
+    for prototype in prototypes(raw, "beacon") {
+        let name = string(prototype, "name")?;
+        let energy_usage = kilo(&prototype["energy_usage"], 'W')?;
+        let pollution = emissions(prototype, energy_usage)?;
+        db.beacons.insert(name.clone(), Arc::new(Beacon { name, energy_usage, pollution }));
+    }
+    for prototype in prototypes(raw, "module") {
+        let name = string(prototype, "name")?;
+        let bonus = |effect: &str| optional_rational(&prototype["effect"][effect]["bonus"]);
+        let (speed, consumption, pollution, productivity) = (bonus("speed")?, bonus("consumption")?, bonus("pollution")?, bonus("productivity")?);
+        db.modules.insert(name.clone(), Arc::new(Module { name, speed, consumption, pollution, productivity }));
+    }
+    for prototype in prototypes(raw, "electric-pole") {
+        let name = string(prototype, "name")?;
+        let supply_area_distance = rational(&prototype["supply_area_distance"])?;
+        let wire_reach = rational(&prototype["maximum_wire_distance"])?;
+        db.poles.insert(name.clone(), Pole { name, supply_area_distance, wire_reach });
+    }
+    for prototype in prototypes(raw, "pipe") {
+        let name = string(prototype, "name")?;
+        // nothing in the prototypes links the two, but they're named alike
+        let underground = format!("{}-to-ground", name);
+        let underground = prototypes(raw, "pipe-to-ground").any(|p| p["name"] == underground.as_str()).then_some(underground);
+        db.pipes.insert(name.clone(), Pipe { name, underground });
+    }
+    for prototype in prototypes(raw, "technology") {
+        let technology = technology(prototype)?;
+        db.technologies.insert(technology.name.clone(), technology);
+    }
+    db
 }
 
-fn normalize_item_spec(table: Table) -> Result<ItemSpec> {
-    let mut items = Vec::new();
-    for item in table.sequence_values::<Table>() {
-        let item = item?;
-        let name: String;
-        let amount: i32;
-        if item.contains_key("name")? {
-            name = item.get("name")?;
-            amount = item.get("amount")?;
-        } else {
-            name = item.get(1)?;
-            amount = item.get(2)?;
+#[throws]
+fn technology(prototype: &Value) -> Technology {
+    let name = string(prototype, "name")?;
+    // some technologies only differ by difficulty in their cost, so take the normal effects
+    let field = |key: &str| match &prototype[key] {
+        Value::Null => &prototype["normal"][key],
+        value => value,
+    };
+    let mut technology = Technology {
+        name,
+        prerequisites: list(field("prerequisites")).filter_map(Value::as_str).map(str::to_owned).collect(),
+        unlocks: vec![],
+        inserter_stack_size_bonus: 0,
+        stack_inserter_capacity_bonus: 0,
+        mining_productivity: Rational::from(0),
+    };
+    for effect in list(field("effects")) {
+        let modifier = &effect["modifier"];
+        match effect["type"].as_str() {
+            Some("unlock-recipe") => technology.unlocks.push(string(effect, "recipe")?),
+            Some("inserter-stack-size-bonus") => technology.inserter_stack_size_bonus += rational(modifier)?.to_integer(),
+            Some("stack-inserter-capacity-bonus") => technology.stack_inserter_capacity_bonus += rational(modifier)?.to_integer(),
+            Some("mining-drill-productivity-bonus") => technology.mining_productivity += rational(modifier)?,
+            _ => {}
+        }
+    }
+    technology
+}
+
+#[throws]
+fn machine(prototype: &Value) -> Machine {
+    let name = string(prototype, "name")?;
+    let size = match (position(&prototype["collision_box"][0]), position(&prototype["collision_box"][1])) {
+        (Some((x1, _)), Some((x2, _))) => (x2 - x1).ceil() as i32,
+        _ => throw!(Error::Prototype(format!("no collision box for {}", name))),
+    };
+    let categories: Vec<String> = list(&prototype["crafting_categories"]).filter_map(Value::as_str).map(str::to_owned).collect();
+    let crafts = |category: &str| categories.iter().any(|c| c == category);
+    let kind = if prototype["type"] == "furnace" {
+        MachineKind::Furnace
+    } else if prototype["type"] == "rocket-silo" {
+        MachineKind::RocketSilo
+    } else if crafts("oil-processing") {
+        MachineKind::OilRefinery
+    } else if crafts("chemistry") {
+        MachineKind::ChemicalLab
+    } else if crafts("centrifuging") {
+        MachineKind::Centrifuge
+    } else {
+        MachineKind::Assembler
+    };
+    let electric = prototype["energy_source"]["type"] == "electric";
+    let energy_usage = kilo(&prototype["energy_usage"], 'W')?;
+    let drain = match prototype["energy_source"]["drain"] {
+        Value::Null if electric => energy_usage / 30,
+        Value::Null => Rational::from(0),
+        ref drain => kilo(drain, 'W')?,
+    };
+    let pollution = emissions(prototype, energy_usage)?;
+    let fluid_boxes = sequence(&prototype["fluid_boxes"]).into_iter().filter_map(|b| {
+        let connection = sequence(&b["pipe_connections"]).into_iter().next()?;
+        // relative to the center, in tiles
+        let (x, y) = position(&connection["position"]).or_else(|| position(&connection["positions"][0]))?;
+        let tile = |p: f64| (size as f64 / 2. + p - 0.5).round() as i32;
+        Some(FluidBox { input: b["production_type"] != "output", position: (tile(x), tile(y)) })
+    }).collect();
+    Machine {
+        kind,
+        electric,
+        crafting_speed: rational(&prototype["crafting_speed"])?,
+        categories,
+        module_slots: prototype["module_specification"]["module_slots"].as_u64().unwrap_or(0) as usize,
+        fluid_boxes,
+        size,
+        energy_usage,
+        drain,
+        pollution,
+        name,
+    }
+}
+
+/// Pollution per minute of an entity drawing `energy_usage` kW
+#[throws]
+fn emissions(prototype: &Value, energy_usage: Rational) -> Rational {
+    // older versions give it relative to the power
+    match prototype["energy_source"]["emissions_per_second_per_watt"].as_f64() {
+        Some(per_watt) => Rational::approximate_float(per_watt * 60. * 1000. * lp::to_f64(energy_usage))
+            .ok_or_else(|| Error::Prototype(format!("bad emissions for {}", prototype["name"])))?,
+        None => optional_rational(&prototype["energy_source"]["emissions_per_minute"])?,
+    }
+}
+
+/// `{x, y}` or `{x = x, y = y}`
+fn position(value: &Value) -> Option<(f64, f64)> {
+    let x = value.get(0).or_else(|| value.get("x"))?.as_f64()?;
+    let y = value.get(1).or_else(|| value.get("y"))?.as_f64()?;
+    Some((x, y))
+}
+
+/// Like [`list`], but Lua tables that also have named keys come out as objects with numeric keys
+fn sequence(value: &Value) -> Vec<&Value> {
+    match value {
+        Value::Array(values) => values.iter().collect(),
+        Value::Object(map) => {
+            let mut values: Vec<(usize, &Value)> = map.iter().filter_map(|(k, v)| Some((k.parse().ok()?, v))).collect();
+            values.sort_by_key(|&(i, _)| i);
+            values.into_iter().map(|(_, v)| v).collect()
         }
-        let kind = match item.get::<_, String>("type") {
-            Ok(s) if s == "fluid" => WireKind::Pipe(name.to_owned()),
-            _ => WireKind::Belt,
+        _ => vec![],
+    }
+}
+
+/// The `prototype` of one difficulty of `recipe` (or all of it), which has the defaults
+#[throws]
+fn variant(prototype: &Value, recipe: &Value, fluids: &FnvHashSet<&str>) -> Variant {
+    let results = match prototype["result"].as_str() {
+        Some(r) => vec![Ingredient::new(r, Rational::from(prototype["result_count"].as_i64().unwrap_or(1) as i32), kind_of(r, None, fluids))],
+        _ => normalize_item_spec(&prototype["results"], fluids)?,
+    };
+    Variant {
+        ingredients: normalize_item_spec(&prototype["ingredients"], fluids)?,
+        results,
+        crafting_time: prototype["energy_required"].as_f64().unwrap_or(0.5),
+        // only the selected difficulty decides, as with the game
+        enabled: prototype["enabled"].as_bool().or_else(|| recipe["enabled"].as_bool()).unwrap_or(true),
+    }
+}
+
+/// Lua tables that are empty come out as `{}` rather than `[]`
+fn list(value: &Value) -> impl Iterator<Item = &Value> {
+    value.as_array().into_iter().flatten()
+}
+
+#[throws]
+fn string(prototype: &Value, key: &str) -> String {
+    match prototype[key].as_str() {
+        Some(s) => s.to_owned(),
+        None => throw!(Error::Prototype(format!("no {} in {}", key, prototype))),
+    }
+}
+
+fn kind_of(name: &str, ty: Option<&str>, fluids: &FnvHashSet<&str>) -> WireKind {
+    match ty {
+        Some("fluid") => WireKind::Pipe(name.to_owned()),
+        Some(_) => WireKind::Belt,
+        None if fluids.contains(name) => WireKind::Pipe(name.to_owned()),
+        None => WireKind::Belt,
+    }
+}
+
+#[throws]
+fn rational(value: &Value) -> Rational {
+    match value.as_f64().and_then(Rational::approximate_float) {
+        Some(x) => x,
+        None => throw!(Error::Prototype(format!("{} is not a number", value))),
+    }
+}
+
+/// `"150kW"` in kW for `unit` W, or `"5kJ"` in kJ for J. Zero if there's none.
+#[throws]
+fn kilo(value: &Value, unit: char) -> Rational {
+    let s = match value.as_str() {
+        Some(s) => s,
+        None => return Rational::from(0),
+    };
+    let (number, scale) = match s.strip_suffix(unit) {
+        Some(s) if s.ends_with('k') => (&s[..s.len() - 1], 1.),
+        Some(s) if s.ends_with('M') => (&s[..s.len() - 1], 1e3),
+        Some(s) if s.ends_with('G') => (&s[..s.len() - 1], 1e6),
+        Some(s) => (s, 1e-3),
+        None => throw!(Error::Prototype(format!("{} is not in {}", s, unit))),
+    };
+    match number.parse::<f64>().ok().and_then(|x| Rational::approximate_float(x * scale)) {
+        Some(x) => x,
+        None => throw!(Error::Prototype(format!("{} is not in {}", s, unit))),
+    }
+}
+
+/// Like [`rational`], but zero if there's none
+#[throws]
+fn optional_rational(value: &Value) -> Rational {
+    if value.is_null() { Rational::from(0) } else { rational(value)? }
+}
+
+#[throws]
+fn normalize_item_spec(spec: &Value, fluids: &FnvHashSet<&str>) -> ItemSpec {
+    let mut items = Vec::new();
+    for item in list(spec) {
+        let name = match item.get("name").or_else(|| item.get(0)).and_then(Value::as_str) {
+            Some(name) => name,
+            None => throw!(Error::Prototype(format!("bad ingredient {}", item))),
         };
-        items.push(Ingredient { name, amount: Rational::from(amount), kind });
+        let amount = match item.get("amount").or_else(|| item.get(1)) {
+            Some(amount) => rational(amount)?,
+            None => {
+                let min = rational(&item["amount_min"])?;
+                (min + rational(&item["amount_max"])?.max(min)) / 2
+            }
+        };
+        let catalyst = match item.get("catalyst_amount") {
+            Some(catalyst) => rational(catalyst)?.min(amount),
+            None => Rational::from(0),
+        };
+        let probability = match item.get("probability") {
+            Some(p) => rational(p)?,
+            None => Rational::from(1),
+        };
+        let temperature = match (item["temperature"].as_f64(), item["minimum_temperature"].as_f64(), item["maximum_temperature"].as_f64()) {
+            (Some(t), _, _) => Some(Temperature { min: t, max: t }),
+            (None, None, None) => None,
+            (None, min, max) => Some(Temperature { min: min.unwrap_or(f64::NEG_INFINITY), max: max.unwrap_or(f64::INFINITY) }),
+        };
+        let kind = kind_of(name, item["type"].as_str(), fluids);
+        items.push(Ingredient { name: name.to_owned(), amount: amount * probability, kind, catalyst: catalyst * probability, temperature });
+    }
+    items
+}
+
+/// Recipes and machines for the tests of other modules, to be adjusted with struct update syntax
+#[cfg(test)]
+pub mod test_util {
+    use super::*;
+
+    pub fn item(name: &str, amount: i32) -> Ingredient {
+        Ingredient::new(name, Rational::from(amount), WireKind::Belt)
+    }
+
+    pub fn fluid(name: &str, amount: i32) -> Ingredient {
+        Ingredient::new(name, Rational::from(amount), WireKind::Pipe(name.to_owned()))
+    }
+
+    /// Named after its first result, crafted in half a second
+    pub fn recipe(ingredients: Vec<Ingredient>, results: Vec<Ingredient>) -> Recipe {
+        Recipe {
+            name: results[0].name.clone(),
+            ingredients,
+            results,
+            category: "crafting".to_owned(),
+            crafting_time: 0.5,
+            allow_productivity: true,
+            enabled: true,
+            compound: None,
+            variants: None,
+        }
+    }
+
+    /// An electric 3x3 assembler without module slots, at crafting speed 1
+    pub fn machine(name: &str, categories: &[&str]) -> Machine {
+        Machine {
+            name: name.to_owned(),
+            kind: MachineKind::Assembler,
+            electric: true,
+            crafting_speed: Rational::from(1),
+            categories: categories.iter().map(|&c| c.to_owned()).collect(),
+            module_slots: 0,
+            fluid_boxes: vec![],
+            size: 3,
+            energy_usage: Rational::from(150),
+            drain: Rational::from(5),
+            pollution: Rational::from(3),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn data_raw_dump() {
+        let raw: RawData = serde_json::from_str(r#"{
+            "fluid": { "water": { "type": "fluid", "name": "water" } },
+            "module": {
+                "productivity-module": { "name": "productivity-module", "category": "productivity", "limitation": ["iron-gear-wheel"] }
+            },
+            "recipe": {
+                "iron-gear-wheel": { "name": "iron-gear-wheel", "ingredients": [["iron-plate", 2]], "result": "iron-gear-wheel" },
+                "concrete": {
+                    "name": "concrete", "category": "crafting-with-fluid", "energy_required": 10,
+                    "ingredients": [["stone-brick", 5], ["iron-ore", 1], { "type": "fluid", "name": "water", "amount": 100 }],
+                    "result": "concrete", "result_count": 10
+                },
+                "steam": { "name": "steam", "category": "chemistry", "ingredients": {}, "results": [{ "name": "water", "amount": 1 }] }
+            }
+        }"#).unwrap();
+        let recipes = recipes_from_raw(&raw).unwrap();
+        let names: Vec<_> = recipes.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["concrete", "iron-gear-wheel", "steam"]);
+
+        let concrete = &recipes[0];
+        assert_eq!(concrete.crafting_time, 10.0);
+        assert_eq!(concrete.ingredients[2].kind, WireKind::Pipe("water".to_owned()));
+        assert_eq!(concrete.results[0].amount, Rational::from(10));
+        assert!(!concrete.allow_productivity);
+        assert!(recipes[1].allow_productivity);
+        assert_eq!(recipes[1].crafting_time, 0.5);
+        // no type, but water is a fluid
+        assert!(recipes[2].ingredients.is_empty());
+        assert_eq!(recipes[2].results[0].kind, WireKind::Pipe("water".to_owned()));
+    }
+
+    #[test]
+    fn prototypes() {
+        let raw: RawData = serde_json::from_str(r#"{
+            "item": { "iron-plate": { "name": "iron-plate", "stack_size": 100, "subgroup": "raw-material" } },
+            "tool": { "automation-science-pack": { "name": "automation-science-pack", "stack_size": 200 } },
+            "assembling-machine": {
+                "chemical-plant": {
+                    "name": "chemical-plant", "crafting_speed": 1, "crafting_categories": ["chemistry"],
+                    "collision_box": [[-1.2, -1.2], [1.2, 1.2]], "module_specification": { "module_slots": 3 }, "energy_usage": "210kW",
+                    "energy_source": { "type": "electric", "emissions_per_minute": 4 },
+                    "fluid_boxes": {
+                        "1": { "production_type": "input", "pipe_connections": [{ "type": "input", "position": [-1, -2] }] },
+                        "2": { "production_type": "input", "pipe_connections": [{ "type": "input", "position": [1, -2] }] },
+                        "3": { "production_type": "output", "pipe_connections": [{ "position": [-1, 2] }] },
+                        "off_when_no_fluid_recipe": false
+                    }
+                },
+                "oil-refinery": {
+                    "name": "oil-refinery", "crafting_speed": 1, "crafting_categories": ["oil-processing"],
+                    "collision_box": [[-2.4, -2.4], [2.4, 2.4]], "energy_usage": "0.42MW",
+                    "energy_source": { "type": "electric", "drain": "14kW", "emissions_per_minute": 6 },
+                    "fluid_boxes": [{ "production_type": "input", "pipe_connections": [{ "position": { "x": -1, "y": 3 } }] }]
+                }
+            },
+            "transport-belt": { "fast-transport-belt": { "name": "fast-transport-belt", "speed": 0.0625, "related_underground_belt": "fast-underground-belt" } },
+            "underground-belt": { "fast-underground-belt": { "name": "fast-underground-belt", "speed": 0.0625 } },
+            "splitter": {
+                "splitter": { "name": "splitter", "speed": 0.03125 },
+                "fast-splitter": { "name": "fast-splitter", "speed": 0.0625 }
+            },
+            "inserter": {
+                "stack-inserter": {
+                    "name": "stack-inserter", "rotation_speed": 0.04, "extension_speed": 0.07, "stack": true,
+                    "energy_per_movement": "20kJ", "energy_per_rotation": "20kJ", "energy_source": { "type": "electric", "drain": "1kW" }
+                }
+            },
+            "beacon": {
+                "beacon": {
+                    "name": "beacon", "energy_usage": "480kW",
+                    "energy_source": { "type": "electric", "usage_priority": "secondary-input", "emissions_per_minute": 0.5 }
+                }
+            },
+            "electric-pole": {
+                "medium-electric-pole": { "name": "medium-electric-pole", "supply_area_distance": 3.5, "maximum_wire_distance": 9 }
+            },
+            "pipe": { "pipe": { "name": "pipe" }, "copper-pipe": { "name": "copper-pipe" } },
+            "pipe-to-ground": { "pipe-to-ground": { "name": "pipe-to-ground" } }
+        }"#).unwrap();
+        let db = prototypes_from_raw(&raw).unwrap();
+        assert_eq!(db.stack_size("iron-plate"), 100);
+        assert_eq!(db.stack_size("automation-science-pack"), 200);
+        assert_eq!(db.items["iron-plate"].subgroup.as_deref(), Some("raw-material"));
+
+        let plant = db.machine("chemical-plant").unwrap();
+        assert_eq!((plant.kind, plant.size, plant.module_slots), (MachineKind::ChemicalLab, 3, 3));
+        assert_eq!(db.machines_for("oil-processing").map(|m| m.name.as_str()).collect::<Vec<_>>(), ["oil-refinery"]);
+        let ports: Vec<_> = plant.fluid_boxes.iter().map(|b| (b.input, b.position)).collect();
+        assert_eq!(ports, [(true, (0, -1)), (true, (2, -1)), (false, (0, 3))]);
+        let refinery = db.machine("oil-refinery").unwrap();
+        assert_eq!(refinery.size, 5);
+        assert_eq!(refinery.fluid_boxes[0].position, (1, 5));
+        assert_eq!((plant.energy_usage, refinery.energy_usage), (Rational::from(210), Rational::from(420)));
+        assert_eq!((plant.drain, refinery.drain), (Rational::from(7), Rational::from(14)));
+        assert_eq!((plant.pollution, refinery.pollution), (Rational::from(4), Rational::from(6)));
+
+        let fast = db.belt(BeltType::Fast).unwrap();
+        assert_eq!(fast.lane_items_per_second, Rational::from(15));
+        assert_eq!(fast.underground.as_deref(), Some("fast-underground-belt"));
+        assert_eq!(fast.splitter.as_deref(), Some("fast-splitter"));
+        assert!(db.belt(BeltType::Express).is_err());
+
+        let stack = db.inserter(InserterKind::Stack).unwrap();
+        assert_eq!(stack.rotation_speed, Rational::new(1, 25));
+        assert!(stack.stack);
+        assert_eq!((stack.energy_usage, stack.drain, stack.pollution), (Rational::from(132), Rational::from(1), Rational::from(0)));
+
+        let beacon = db.beacon("beacon").unwrap();
+        assert_eq!((beacon.energy_usage, beacon.pollution), (Rational::from(480), Rational::new(1, 2)));
+        let pole = db.pole("medium-electric-pole").unwrap();
+        assert_eq!((pole.supply_area_distance, pole.wire_reach), (Rational::new(7, 2), Rational::from(9)));
+        assert_eq!(db.pipe("pipe").unwrap().underground.as_deref(), Some("pipe-to-ground"));
+        assert_eq!(db.pipe("copper-pipe").unwrap().underground, None);
+    }
+
+    #[test]
+    fn research() {
+        let raw: RawData = serde_json::from_str(r#"{
+            "recipe": {
+                "iron-gear-wheel": { "name": "iron-gear-wheel", "ingredients": [["iron-plate", 2]], "result": "iron-gear-wheel" },
+                "fast-inserter": { "name": "fast-inserter", "enabled": false, "ingredients": [["inserter", 1]], "result": "fast-inserter" },
+                "stack-inserter": {
+                    "name": "stack-inserter",
+                    "normal": { "enabled": false, "ingredients": [["fast-inserter", 1]], "result": "stack-inserter" }
+                }
+            },
+            "technology": {
+                "fast-inserter": {
+                    "name": "fast-inserter", "effects": [{ "type": "unlock-recipe", "recipe": "fast-inserter" }]
+                },
+                "stack-inserter": {
+                    "name": "stack-inserter", "prerequisites": ["fast-inserter"],
+                    "effects": [
+                        { "type": "unlock-recipe", "recipe": "stack-inserter" },
+                        { "type": "stack-inserter-capacity-bonus", "modifier": 1 }
+                    ]
+                },
+                "inserter-capacity-bonus-2": {
+                    "name": "inserter-capacity-bonus-2", "prerequisites": ["stack-inserter"],
+                    "normal": {
+                        "effects": [
+                            { "type": "inserter-stack-size-bonus", "modifier": 1 },
+                            { "type": "stack-inserter-capacity-bonus", "modifier": 1 }
+                        ]
+                    }
+                },
+                "mining-productivity-1": {
+                    "name": "mining-productivity-1", "effects": [{ "type": "mining-drill-productivity-bonus", "modifier": 0.1 }]
+                }
+            }
+        }"#).unwrap();
+        let db = Database { recipes: recipes_from_raw(&raw).unwrap(), prototypes: prototypes_from_raw(&raw).unwrap() };
+        let names = |db: &Database| db.recipes.iter().map(|r| r.name.clone()).collect::<Vec<_>>();
+        assert!(db.researched(&Research::All).unwrap().is_none());
+
+        let mut only = db.clone();
+        let technologies = only.researched(&Research::Only(vec!["fast-inserter".to_owned()])).unwrap().unwrap();
+        only.retain_unlocked(&technologies);
+        assert_eq!(names(&only), ["fast-inserter", "iron-gear-wheel"]);
+
+        let mut up_to = db.clone();
+        let technologies = up_to.researched(&Research::UpTo("inserter-capacity-bonus-2".to_owned())).unwrap().unwrap();
+        assert_eq!(technologies.len(), 3);
+        up_to.retain_unlocked(&technologies);
+        assert_eq!(names(&up_to), ["fast-inserter", "iron-gear-wheel", "stack-inserter"]);
+        let bonus: i32 = technologies.iter().map(|t| t.stack_inserter_capacity_bonus).sum();
+        assert_eq!(bonus, 2);
+
+        let mining = &db.prototypes.technologies["mining-productivity-1"];
+        assert_eq!(mining.mining_productivity, Rational::new(1, 10));
+        assert!(db.researched(&Research::UpTo("rocket-silo".to_owned())).is_err());
+    }
+
+    #[test]
+    fn difficulty() {
+        let raw: RawData = serde_json::from_str(r#"{
+            "recipe": {
+                "electronic-circuit": {
+                    "name": "electronic-circuit",
+                    "normal": { "ingredients": [["iron-plate", 1], ["copper-cable", 3]], "result": "electronic-circuit" },
+                    "expensive": { "energy_required": 1, "ingredients": [["iron-plate", 2], ["copper-cable", 8]], "result": "electronic-circuit" }
+                },
+                "iron-gear-wheel": {
+                    "name": "iron-gear-wheel",
+                    "normal": { "ingredients": [["iron-plate", 2]], "result": "iron-gear-wheel" },
+                    "expensive": false
+                },
+                "stack-inserter": {
+                    "name": "stack-inserter",
+                    "normal": { "enabled": false, "ingredients": [["fast-inserter", 1]], "result": "stack-inserter" },
+                    "expensive": { "ingredients": [["fast-inserter", 2]], "result": "stack-inserter" }
+                }
+            }
+        }"#).unwrap();
+        let mut db = Database { recipes: recipes_from_raw(&raw).unwrap(), prototypes: Prototypes::default() };
+        assert_eq!(db.recipes[0].ingredients[1].amount, Rational::from(3));
+        assert!(!db.recipes[2].enabled);
+
+        db.select(Difficulty::Expensive).unwrap();
+        assert_eq!(db.recipes[0].ingredients[1].amount, Rational::from(8));
+        assert_eq!(db.recipes[0].crafting_time, 1.0);
+        assert_eq!(db.recipes[1].ingredients[0].amount, Rational::from(2));
+        assert!(db.recipes[2].enabled);
+
+        db.select(Difficulty::Normal).unwrap();
+        assert_eq!(db.recipes[0].ingredients[1].amount, Rational::from(3));
+        assert_eq!(db.recipes[0].crafting_time, 0.5);
+        assert!(!db.recipes[2].enabled);
+
+        // the merged ingredients would stay those of the old variant
+        db.recipes[1].compound = Some(Compound { template: crate::overrides::Template::CableCircuit, parts: vec!["copper-cable".to_owned()] });
+        assert!(db.select(Difficulty::Expensive).is_err());
+    }
+
+    #[test]
+    fn result_specs() {
+        let raw: RawData = serde_json::from_str(r#"{
+            "recipe": {
+                "uranium-processing": {
+                    "name": "uranium-processing", "category": "centrifuging", "energy_required": 12,
+                    "ingredients": [["uranium-ore", 10]],
+                    "results": [
+                        { "name": "uranium-235", "amount": 1, "probability": 0.007 },
+                        { "name": "uranium-238", "amount": 1, "probability": 0.993 }
+                    ]
+                },
+                "kovarex-enrichment-process": {
+                    "name": "kovarex-enrichment-process", "category": "centrifuging", "energy_required": 60,
+                    "ingredients": [["uranium-235", 40], ["uranium-238", 5]],
+                    "results": [
+                        { "name": "uranium-235", "amount": 41, "catalyst_amount": 40 },
+                        { "name": "uranium-238", "amount": 2, "catalyst_amount": 2 }
+                    ]
+                },
+                "scrap-recycling": {
+                    "name": "scrap-recycling",
+                    "ingredients": [{ "type": "fluid", "name": "steam", "amount": 2.5, "minimum_temperature": 165 }],
+                    "results": [{ "name": "iron-plate", "amount_min": 1, "amount_max": 4, "probability": 0.5 }]
+                }
+            }
+        }"#).unwrap();
+        let recipes = recipes_from_raw(&raw).unwrap();
+        let kovarex = &recipes[0];
+        assert_eq!(kovarex.results[0].with_productivity(Rational::new(1, 10)), Rational::new(411, 10));
+        assert_eq!(kovarex.results[1].with_productivity(Rational::new(1, 10)), Rational::from(2));
+
+        let recycling = &recipes[1];
+        assert_eq!(recycling.ingredients[0].amount, Rational::new(5, 2));
+        assert_eq!(recycling.ingredients[0].temperature, Some(Temperature { min: 165., max: f64::INFINITY }));
+        assert_eq!(recycling.results[0].amount, Rational::new(5, 4));
+
+        let uranium = &recipes[2];
+        assert_eq!(uranium.results[0].amount, Rational::new(7, 1000));
+        assert_eq!(uranium.results[1].amount, Rational::new(993, 1000));
     }
-    Ok(items)
 }
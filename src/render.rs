@@ -3,8 +3,12 @@
 use std::iter::{self, FromIterator};
 use std::borrow::Borrow;
 use std::collections::HashMap;
+use std::sync::Arc;
 
+use crate::consts::Constants;
+use crate::error::{Error, Result};
 use crate::pcb::{Pcb, Entity, Function, Direction, Rect, Point, InserterKind};
+use crate::recipe::{Module, Prototypes};
 use crate::routing::LogisticRoute;
 
 #[must_use]
@@ -86,10 +90,17 @@ pub fn ascii_routed_wire(rows: &Vec<Vec<bool>>, path: &Vec<(i32, i32)>) -> Strin
     res
 }
 
-#[must_use]
-pub fn blueprint(pcb: &impl Pcb) -> String {
+pub fn blueprint(pcb: &impl Pcb, consts: &Constants, prototypes: &Prototypes) -> Result<String> {
     use factorio_blueprint::{objects::*, BlueprintCodec, Container};
 
+    let module_items = |modules: &[Arc<Module>]| -> Option<ItemRequest> {
+        let mut items = ItemRequest::new();
+        for m in modules {
+            *items.entry(m.name.clone()).or_default() += 1;
+        }
+        Some(items).filter(|i| !i.is_empty())
+    };
+
     let container = Container::Blueprint(Blueprint {
         item: "blueprint".to_owned(),
         label: "very cool".to_owned(),
@@ -103,7 +114,7 @@ pub fn blueprint(pcb: &impl Pcb) -> String {
         tiles: vec![],
         entities: pcb.entities()
             .enumerate()
-            .map(|(i, e)| {
+            .map(|(i, e)| -> std::result::Result<Entity, Error> {
                 let mut underground_type = None;
                 let mut recipe = None;
                 let mut direction = None;
@@ -112,18 +123,40 @@ pub fn blueprint(pcb: &impl Pcb) -> String {
                     y: (e.location.y as f64).try_into().unwrap(),
                 };
                 let mut filters = None;
-                let name = match e.function {
-                    Function::Assembler { recipe: ref r } => {
+                let mut items = None;
+                // machines are positioned by their center
+                if let Some(machine) = e.machine() {
+                    position.x += machine.size as f64 / 2. - 0.5;
+                    position.y += machine.size as f64 / 2. - 0.5;
+                }
+                let name: &str = match e.function {
+                    Function::Assembler { recipe: ref r, ref machine, ref modules } => {
                         recipe = Some(r.clone());
+                        items = module_items(modules);
                         direction = Some(Direction::Right);
-                        position.x += 1.;
-                        position.y += 1.;
-                        "assembling-machine-2"
+                        &machine.name
                     },
-                    Function::Furnace => {
+                    Function::Furnace { ref machine, ref modules } => {
+                        items = module_items(modules);
+                        &machine.name
+                    }
+                    Function::ChemicalPlant { recipe: ref r, ref machine, orientation, ref modules }
+                    | Function::OilRefinery { recipe: ref r, ref machine, orientation, ref modules } => {
+                        recipe = Some(r.clone());
+                        items = module_items(modules);
+                        direction = Some(orientation);
+                        &machine.name
+                    }
+                    Function::Centrifuge { recipe: ref r, ref machine, ref modules } => {
+                        recipe = Some(r.clone());
+                        items = module_items(modules);
+                        &machine.name
+                    }
+                    Function::Beacon { ref beacon, ref modules } => {
+                        items = module_items(modules);
                         position.x += 1.;
                         position.y += 1.;
-                        "electric-furnace"
+                        &beacon.name
                     }
                     Function::Inserter { orientation, kind } => {
                         // reverse direction because the game thinks about these differently than we
@@ -134,44 +167,42 @@ pub fn blueprint(pcb: &impl Pcb) -> String {
                             Direction::Left => Direction::Right,
                             Direction::Right => Direction::Left,
                         });
-                        match kind {
-                            InserterKind::Normal => "inserter",
-                            InserterKind::LongHanded => "long-handed-inserter",
-                            InserterKind::Fast => "fast-inserter",
-                            InserterKind::Stack => "stack-inserter",
-                        }
+                        &prototypes.inserter(kind)?.name
                     },
-                    Function::Belt(d) => {
+                    Function::Belt(d, belt) => {
                         direction = Some(d);
-                        "transport-belt"
+                        &prototypes.belt(belt)?.name
                     },
-                    Function::UndergroundBelt(d, down) => {
+                    Function::UndergroundBelt(d, down, belt) => {
                         direction = Some(d);
                         underground_type =
                             Some(if down { EntityType::Input } else { EntityType::Output });
-                        "underground-belt"
+                        prototypes.belt(belt)?.underground.as_deref()
+                            .ok_or_else(|| Error::MissingPrototype(format!("underground belt for {}", belt.belt_name())))?
                     },
-                    Function::Splitter(d) => {
+                    Function::Splitter(d, belt) => {
                         direction = Some(d);
                         match d {
                             Direction::Up | Direction::Down => position.x += 0.5,
                             Direction::Left | Direction::Right => position.y += 0.5,
                         }
-                        "splitter"
+                        prototypes.belt(belt)?.splitter.as_deref()
+                            .ok_or_else(|| Error::MissingPrototype(format!("splitter for {}", belt.belt_name())))?
                     }
-                    Function::ElectricPole => "medium-electric-pole",
+                    Function::ElectricPole => &prototypes.pole(&consts.pole)?.name,
                     Function::InputMarker(ref i) => {
                         filters = Some(vec![ItemFilter { name: i.clone(), index: OneBasedIndex::new(1).unwrap() }]);
-                        "filter-inserter"
+                        &prototypes.inserter(InserterKind::Filter)?.name
                     }
-                    Function::Pipe(_) => "pipe",
+                    Function::Pipe(_) => &prototypes.pipe(&consts.pipe)?.name,
                     Function::UndergroundPipe(d) => {
                         direction = Some(d);
-                        "pipe-to-ground"
+                        prototypes.pipe(&consts.pipe)?.underground.as_deref()
+                            .ok_or_else(|| Error::MissingPrototype(format!("pipe-to-ground for {}", consts.pipe)))?
                     }
                 };
 
-                Entity {
+                Ok(Entity {
                     entity_number: EntityNumber::new(i + 1).unwrap(),
                     name: name.to_owned(),
                     position,
@@ -184,14 +215,14 @@ pub fn blueprint(pcb: &impl Pcb) -> String {
                     orientation: None,
                     connections: None,
                     control_behaviour: None,
-                    items: None,
+                    items,
                     recipe,
                     bar: None,
                     inventory: None,
                     infinity_settings: None,
                     type_: underground_type,
-                    input_priority: if name == "splitter" { Some(EntityPriority::Right) } else { None },
-                    output_priority: if name == "splitter" { Some(EntityPriority::Left) } else { None },
+                    input_priority: if let Function::Splitter(..) = e.function { Some(EntityPriority::Right) } else { None },
+                    output_priority: if let Function::Splitter(..) = e.function { Some(EntityPriority::Left) } else { None },
                     filter: None,
                     filters,
                     filter_mode: None,
@@ -206,11 +237,11 @@ pub fn blueprint(pcb: &impl Pcb) -> String {
                     variation: None,
                     color: None,
                     station: None,
-                }
+                })
             })
-            .collect(),
+            .collect::<std::result::Result<_, Error>>()?,
     });
-    BlueprintCodec::encode_string(&container).unwrap()
+    BlueprintCodec::encode_string(&container).map_err(|e| Error::Encoding(e.to_string()))
 }
 
 struct AsciiCanvas {
@@ -239,28 +270,19 @@ impl AsciiCanvas {
 
         for e in entities {
             let symbol = match e.function {
-                Function::Assembler { ref recipe } => {
-                    canvas.set(e.location.x + 0, e.location.y + 0, '┌');
-                    canvas.set(e.location.x + 1, e.location.y + 0, '─');
-                    canvas.set(e.location.x + 2, e.location.y + 0, '┐');
-                    canvas.set(e.location.x + 0, e.location.y + 1, '│');
-                    canvas.set(e.location.x + 1, e.location.y + 1, recipe.to_uppercase().chars().next().unwrap());
-                    canvas.set(e.location.x + 2, e.location.y + 1, '│');
-                    canvas.set(e.location.x + 0, e.location.y + 2, '└');
-                    canvas.set(e.location.x + 1, e.location.y + 2, '─');
-                    canvas.set(e.location.x + 2, e.location.y + 2, '┘');
+                Function::Assembler { ref recipe, .. }
+                | Function::ChemicalPlant { ref recipe, .. }
+                | Function::OilRefinery { ref recipe, .. }
+                | Function::Centrifuge { ref recipe, .. } => {
+                    canvas.building(e, recipe.to_uppercase().chars().next().unwrap());
                     continue;
                 },
-                Function::Furnace => {
-                    canvas.set(e.location.x + 0, e.location.y + 0, '┌');
-                    canvas.set(e.location.x + 1, e.location.y + 0, '─');
-                    canvas.set(e.location.x + 2, e.location.y + 0, '┐');
-                    canvas.set(e.location.x + 0, e.location.y + 1, '│');
-
-                    canvas.set(e.location.x + 2, e.location.y + 1, '│');
-                    canvas.set(e.location.x + 0, e.location.y + 2, '└');
-                    canvas.set(e.location.x + 1, e.location.y + 2, '─');
-                    canvas.set(e.location.x + 2, e.location.y + 2, '┘');
+                Function::Furnace { .. } => {
+                    canvas.building(e, ' ');
+                    continue;
+                }
+                Function::Beacon { .. } => {
+                    canvas.building(e, '*');
                     continue;
                 }
                 Function::Inserter { orientation: d, kind } => {
@@ -280,7 +302,7 @@ impl AsciiCanvas {
                         }
                     }
                 },
-                Function::Belt(d) => {
+                Function::Belt(d, _) => {
                     match d {
                         Direction::Up => '⍐',
                         Direction::Down => '⍗',
@@ -288,7 +310,7 @@ impl AsciiCanvas {
                         Direction::Right => '⍈',
                     }
                 },
-                Function::UndergroundBelt(d, down) => {
+                Function::UndergroundBelt(d, down, _) => {
                     if down {
                         match d {
                             Direction::Up => '⍓',
@@ -305,7 +327,7 @@ impl AsciiCanvas {
                         }
                     }
                 },
-                Function::Splitter(d) => {
+                Function::Splitter(d, _) => {
                     match d {
                         Direction::Up | Direction::Down => canvas.set(e.location.x + 1, e.location.y, 'X'),
                         Direction::Left | Direction::Right => canvas.set(e.location.x, e.location.y + 1, 'X'),
@@ -325,6 +347,24 @@ impl AsciiCanvas {
         canvas
     }
 
+    fn building(&mut self, e: &Entity, symbol: char) {
+        let (x, y) = (e.location.x, e.location.y);
+        let (w, h) = (e.size_x() - 1, e.size_y() - 1);
+        for i in 1..w {
+            self.set(x + i, y, '─');
+            self.set(x + i, y + h, '─');
+        }
+        for i in 1..h {
+            self.set(x, y + i, '│');
+            self.set(x + w, y + i, '│');
+        }
+        self.set(x, y, '┌');
+        self.set(x + w, y, '┐');
+        self.set(x, y + h, '└');
+        self.set(x + w, y + h, '┘');
+        self.set(x + w / 2, y + h / 2, symbol);
+    }
+
     fn set(&mut self, x: i32, y: i32, c: char) {
         self.canvas[(y + self.offset_y) as usize][(x + self.offset_x) as usize] = c;
     }
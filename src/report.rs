@@ -0,0 +1,190 @@
+//! What a designed factory takes, draws and emits, and what it's built from
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use fehler::throws;
+
+use crate::Rational;
+use crate::consts::Constants;
+use crate::error::Error;
+use crate::kirkmcdonald::ProductionGraph;
+use crate::lp;
+use crate::pcb::{Function, Pcb, PcbRef};
+use crate::recipe::Prototypes;
+
+/// Statistics of a design, everything running flat out
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    /// Inputs per second that nothing in the design makes
+    pub raw: Vec<(String, Rational)>,
+    /// Inputs per second that are [`Constants::external`], though we could make them
+    pub external: Vec<(String, Rational)>,
+    /// Crafting machines built, per prototype
+    pub machines: Vec<(String, usize)>,
+    pub beacons: usize,
+    /// kW with every machine and inserter waiting
+    pub idle_power: Rational,
+    /// kW with every machine crafting and every inserter swinging
+    pub active_power: Rational,
+    pub pollution_per_minute: Rational,
+    /// Counting undergrounds as one each, not pairs
+    pub belts: usize,
+    pub underground_belts: usize,
+    pub splitters: usize,
+    /// Not counting the input markers
+    pub inserters: usize,
+    pub pipes: usize,
+}
+
+impl Report {
+    /// Statistics of `pcb`, which has `graph` placed. The placers build every recipe of it, so
+    /// its external input nodes are what the factory takes in.
+    #[throws]
+    pub fn new<P: Pcb>(graph: &ProductionGraph, pcb: &P, consts: &Constants, prototypes: &Prototypes) -> Self {
+        let mut machines = BTreeMap::<String, usize>::new();
+        let (external, raw): (Vec<_>, Vec<_>) = graph.nodes.iter()
+            .filter(|n| n.recipe.is_none())
+            .map(|n| (n.output.clone(), n.per_second))
+            .partition(|(item, _)| consts.external.contains(item));
+        let mut report = Report {
+            raw,
+            external,
+            machines: vec![],
+            beacons: 0,
+            idle_power: Rational::from(0),
+            active_power: Rational::from(0),
+            pollution_per_minute: Rational::from(0),
+            belts: 0,
+            underground_belts: 0,
+            splitters: 0,
+            inserters: 0,
+            pipes: 0,
+        };
+        for entity in pcb.entities() {
+            match entity.function {
+                Function::Assembler { ref machine, ref modules, .. } | Function::Furnace { ref machine, ref modules }
+                | Function::ChemicalPlant { ref machine, ref modules, .. } | Function::OilRefinery { ref machine, ref modules, .. }
+                | Function::Centrifuge { ref machine, ref modules, .. } => {
+                    *machines.entry(machine.name.clone()).or_default() += 1;
+                    // burner machines burn fuel instead
+                    if machine.electric {
+                        report.idle_power += machine.drain;
+                        report.active_power += machine.drain + consts.machine_power(prototypes, machine, modules)?;
+                    }
+                    report.pollution_per_minute += consts.machine_pollution(prototypes, machine, modules)?;
+                }
+                Function::Beacon { ref beacon, .. } => {
+                    report.beacons += 1;
+                    report.idle_power += beacon.energy_usage;
+                    report.active_power += beacon.energy_usage;
+                    report.pollution_per_minute += beacon.pollution;
+                }
+                Function::Inserter { kind, .. } => {
+                    let inserter = prototypes.inserter(kind)?;
+                    report.inserters += 1;
+                    report.idle_power += inserter.drain;
+                    report.active_power += inserter.drain + inserter.energy_usage;
+                    report.pollution_per_minute += inserter.pollution;
+                }
+                Function::Belt(..) => report.belts += 1,
+                Function::UndergroundBelt(..) => report.underground_belts += 1,
+                Function::Splitter(..) => report.splitters += 1,
+                Function::Pipe(_) | Function::UndergroundPipe(_) => report.pipes += 1,
+                Function::ElectricPole | Function::InputMarker(_) => {}
+            }
+        }
+        report.machines = machines.into_iter().collect();
+        report
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "raw resources:")?;
+        for (item, per_second) in &self.raw {
+            writeln!(f, "  {:<30} {:>10.2}/s", item, lp::to_f64(*per_second))?;
+        }
+        if !self.external.is_empty() {
+            writeln!(f, "external intermediates:")?;
+            for (item, per_second) in &self.external {
+                writeln!(f, "  {:<30} {:>10.2}/s", item, lp::to_f64(*per_second))?;
+            }
+        }
+        writeln!(f, "machines:")?;
+        for (machine, count) in &self.machines {
+            writeln!(f, "  {:<30} {:>10}", machine, count)?;
+        }
+        writeln!(f, "  {:<30} {:>10}", "beacon", self.beacons)?;
+        writeln!(f, "{:<32} {:>10.1} kW", "power (active)", lp::to_f64(self.active_power))?;
+        writeln!(f, "{:<32} {:>10.1} kW", "power (idle)", lp::to_f64(self.idle_power))?;
+        writeln!(f, "{:<32} {:>10.1}/min", "pollution", lp::to_f64(self.pollution_per_minute))?;
+        writeln!(f, "{:<32} {:>10}", "belts", self.belts)?;
+        writeln!(f, "{:<32} {:>10}", "underground belts", self.underground_belts)?;
+        writeln!(f, "{:<32} {:>10}", "splitters", self.splitters)?;
+        writeln!(f, "{:<32} {:>10}", "inserters", self.inserters)?;
+        write!(f, "{:<32} {:>10}", "pipes", self.pipes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use crate::consts::BeltType;
+    use crate::kirkmcdonald::ProductionNode;
+    use crate::pcb::{Direction, Entity, GridPcb, InserterKind, Point, WireKind};
+    use crate::recipe::{Beacon, Inserter, Machine};
+    use crate::recipe::test_util::machine;
+
+    #[test]
+    fn totals() {
+        let mut prototypes = Prototypes::default();
+        prototypes.inserters.insert("inserter".to_owned(), Inserter {
+            name: "inserter".to_owned(),
+            rotation_speed: Rational::new(7, 500),
+            stack: false,
+            energy_usage: Rational::new(66, 5),
+            drain: Rational::new(2, 5),
+            pollution: Rational::from(0),
+        });
+        let beacon = Arc::new(Beacon { name: "beacon".to_owned(), energy_usage: Rational::from(480), pollution: Rational::new(1, 2) });
+        let mut pcb = GridPcb::default();
+        pcb.add_all(&[
+            Entity { location: Point::new(0, 0), function: Function::Assembler { recipe: "gear".to_owned(), machine: Arc::new(machine("assembler", &[])), modules: vec![] } },
+            Entity { location: Point::new(3, 0), function: Function::Furnace { machine: Arc::new(Machine { electric: false, ..machine("stone-furnace", &[]) }), modules: vec![] } },
+            Entity { location: Point::new(0, 3), function: Function::Inserter { orientation: Direction::Up, kind: InserterKind::Normal } },
+            Entity { location: Point::new(0, 4), function: Function::Belt(Direction::Up, BeltType::Normal) },
+            Entity { location: Point::new(1, 4), function: Function::Belt(Direction::Up, BeltType::Normal) },
+            Entity { location: Point::new(2, 4), function: Function::InputMarker("iron-plate".to_owned()) },
+            Entity { location: Point::new(6, 0), function: Function::Beacon { beacon, modules: vec![] } },
+        ]).unwrap();
+        let input = |item: &str, per_second: i32| ProductionNode {
+            recipe: None,
+            output: item.to_owned(),
+            output_kind: WireKind::Belt,
+            per_second: Rational::from(per_second),
+            how_many: Rational::from(0),
+            building: None,
+            machine: None,
+            modules: vec![],
+            byproducts: vec![],
+            compound: None,
+        };
+        let graph = ProductionGraph { nodes: vec![input("iron-plate", 2), input("copper-cable", 3)], edges: vec![], products: vec![] };
+
+        let consts = Constants { external: vec!["copper-cable".to_owned()], ..Constants::default() };
+
+        let report = Report::new(&graph, &pcb, &consts, &prototypes).unwrap();
+        assert_eq!(report.raw, [("iron-plate".to_owned(), Rational::from(2))]);
+        assert_eq!(report.external, [("copper-cable".to_owned(), Rational::from(3))]);
+        assert_eq!(report.machines, [("assembler".to_owned(), 1), ("stone-furnace".to_owned(), 1)]);
+        assert_eq!(report.beacons, 1);
+        assert_eq!(report.idle_power, Rational::from(480) + Rational::new(27, 5));
+        assert_eq!(report.active_power, Rational::from(480 + 155) + Rational::new(68, 5));
+        assert_eq!(report.pollution_per_minute, Rational::new(13, 2));
+        assert_eq!((report.belts, report.inserters, report.pipes), (2, 1, 0));
+        assert!(report.to_string().contains("iron-plate"));
+        assert!(report.to_string().contains("external intermediates"));
+    }
+}
@@ -5,7 +5,7 @@ use crate::pcb::{Pcb, Direction, Point, ALL_DIRECTIONS, NeededWire};
 use crate::routing::{apply_lee_path, insert_underground_belts};
 
 #[throws(())]
-pub fn lee_pathfinder(pcb: &mut impl Pcb, &NeededWire { from, to, ref wire_kind }: &NeededWire) {
+pub fn lee_pathfinder(pcb: &mut impl Pcb, &NeededWire { from, to, ref wire_kind, belt }: &NeededWire) {
     let lee_rect = pcb.entity_rect().pad(2);
 
     let mut rows = Vec::new();
@@ -32,7 +32,7 @@ pub fn lee_pathfinder(pcb: &mut impl Pcb, &NeededWire { from, to, ref wire_kind
 
 //    println!("{}", render::ascii_routed_wire(&rows, &path2));
     let path = path.into_iter().map(|i| ALL_DIRECTIONS[i]);
-    let path = insert_underground_belts(path, wire_kind.gap_size());
-    apply_lee_path(pcb, Point::new(from.x, from.y), path, wire_kind)
+    let path = insert_underground_belts(path, wire_kind.gap_size(belt));
+    apply_lee_path(pcb, Point::new(from.x, from.y), path, wire_kind, belt).map_err(|_| ())?;
 }
 
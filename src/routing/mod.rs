@@ -1,5 +1,7 @@
 use fehler::throws;
 
+use crate::consts::BeltType;
+use crate::error::{Error, Result};
 use crate::pcb::{Pcb, NeededWires, Entity, Function, Point, Direction, NeededWire, WireKind};
 
 #[cfg(feature = "leemaze_lib")]
@@ -15,7 +17,16 @@ use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use rand::prelude::*;
 
-pub fn route<P: Pcb>(pcb: &mut P, needed_wires: NeededWires, pathfinder_fn: impl Fn(&mut P, &NeededWire) -> Result<(), ()> + Clone + Send + 'static) {
+/// How the winning routing worker arrived at its result
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RoutingStats {
+    pub worker: u64,
+    pub tries: usize,
+    pub total_depth: usize,
+}
+
+#[throws]
+pub fn route<P: Pcb>(pcb: &mut P, needed_wires: NeededWires, pathfinder_fn: impl Fn(&mut P, &NeededWire) -> Result<(), ()> + Clone + Send + 'static) -> RoutingStats {
     // TODO: dynamic thread count; if 1 then don't spawn anything and just run directly on this thread
     let canceled = Arc::new(AtomicBool::new(false));
     let (tx, rx) = std::sync::mpsc::channel();
@@ -32,9 +43,13 @@ pub fn route<P: Pcb>(pcb: &mut P, needed_wires: NeededWires, pathfinder_fn: impl
         });
     }
 
-    *pcb = rx.recv().unwrap();
+    // only the workers hold senders now, so this fails once all of them died without a result
+    drop(tx);
+    let (routed, stats) = rx.recv().map_err(|_| Error::Routing)?;
+    *pcb = routed;
     canceled.store(true, std::sync::atomic::Ordering::SeqCst);
-    reduce_gratuitous_undergrounds(pcb);
+    reduce_gratuitous_undergrounds(pcb)?;
+    stats
 }
 
 pub fn route_worker<P: Pcb>(
@@ -43,7 +58,7 @@ pub fn route_worker<P: Pcb>(
     canceled: &AtomicBool,
     mut needed_wires: NeededWires,
     pathfinder_fn: impl Fn(&mut P, &NeededWire) -> Result<(), ()>
-) -> Option<P> {
+) -> Option<(P, RoutingStats)> {
     // simulated annealing-ish to choose wiring order
     let mut panic = 0;
     let mut temperature = 20;
@@ -57,10 +72,7 @@ pub fn route_worker<P: Pcb>(
             Ok(p) => {
                 total_tries += 1;
                 total_depth += needed_wires.len();
-                println!("[{tid}] total tries: {}", total_tries);
-                println!("[{tid}] total depth: {}", total_depth);
-                println!("[{tid}] averg depth: {:2}", total_depth as f32 / total_tries as f32);
-                return Some(p);
+                return Some((p, RoutingStats { worker: tid, tries: total_tries, total_depth }));
             }
             Err(i) => {
                 let ele = needed_wires.remove(i);
@@ -76,7 +88,6 @@ pub fn route_worker<P: Pcb>(
                 total_depth += i + 1;
                 total_tries += 1;
                 panic += 1;
-                println!("[{tid}] panic={}", panic);
             }
         }
     }
@@ -84,34 +95,36 @@ pub fn route_worker<P: Pcb>(
     None
 }
 
+#[throws]
 fn reduce_gratuitous_undergrounds(pcb: &mut impl Pcb) {
-    collapse_underground_oneway(pcb, true);
-    collapse_underground_oneway(pcb, false);
+    collapse_underground_oneway(pcb, true)?;
+    collapse_underground_oneway(pcb, false)?;
 }
+#[throws]
 fn collapse_underground_oneway(pcb: &mut impl Pcb, down: bool) {
     let candidates: Vec<_> = pcb.entities().filter_map(|e| match e.function {
-        Function::UndergroundBelt(d, mode) if mode == down => Some((e.location, d)),
+        Function::UndergroundBelt(d, mode, belt) if mode == down => Some((e.location, d, belt)),
         _ => None,
     }).collect();
 
-    for (mut pos, dir) in candidates {
+    for (mut pos, dir, belt) in candidates {
         let v = dir.to_vector() * if down { 1 } else { -1 };
         loop {
             let collapse_fully = match pcb.entity_at(pos + v) {
                 None => false,
-                Some(Entity { function: Function::UndergroundBelt(od, mode), .. }) if *od == dir && *mode != down => true,
+                Some(Entity { function: Function::UndergroundBelt(od, mode, ob), .. }) if *od == dir && *mode != down && *ob == belt => true,
 
                 _ => break,
             };
 
             // collapse the entry by one tile and loop
-            pcb.replace(Entity { location: pos, function: Function::Belt(dir) });
+            pcb.replace(Entity { location: pos, function: Function::Belt(dir, belt) })?;
             pos += v;
             if collapse_fully {
-                pcb.replace(Entity { location: pos, function: Function::Belt(dir) });
+                pcb.replace(Entity { location: pos, function: Function::Belt(dir, belt) })?;
                 break;
             } else {
-                pcb.replace(Entity { location: pos, function: Function::UndergroundBelt(dir, down) });
+                pcb.replace(Entity { location: pos, function: Function::UndergroundBelt(dir, down, belt) })?;
             }
         }
     }
@@ -124,10 +137,6 @@ fn try_wiring<P: Pcb>(mut pcb: P,
     pathfinder_fn: &impl Fn(&mut P, &NeededWire) -> Result<(), ()>,
 ) -> P {
     for (i, wire) in needed_wires.iter().enumerate() {
-        // render_blueprint_ascii(&pcb);
-        #[cfg(feature = "render_wiring_steps")]
-        println!("{}", render::ascii(&pcb));
-
         pathfinder_fn(&mut pcb, wire).map_err(|()| i)?;
     }
     pcb
@@ -179,7 +188,6 @@ fn insert_underground_belts<I: IntoIterator<Item=Direction>>(path: I, gap_limit:
             undergrounded_path.push(LogisticRoute::Normal(current_direction));
         } else {
             // insert underground belt
-            // FIXME: pipes can have distance of 9 instead of 4; also other belt types
             let gap = std::cmp::min(tail_length - 2, gap_limit) as i32;
             undergrounded_path.push(LogisticRoute::Underground { dir: current_direction, gap });
             // skip belts we're replacing
@@ -190,7 +198,8 @@ fn insert_underground_belts<I: IntoIterator<Item=Direction>>(path: I, gap_limit:
 }
 
 
-fn apply_lee_path<I: IntoIterator<Item = LogisticRoute>>(pcb: &mut impl Pcb, from: Point, path: I, kind: &WireKind) where I::IntoIter: Clone {
+#[throws]
+fn apply_lee_path<I: IntoIterator<Item = LogisticRoute>>(pcb: &mut impl Pcb, from: Point, path: I, kind: &WireKind, tier: BeltType) where I::IntoIter: Clone {
     let mut cursor = from;
     let path = path.into_iter();
     // println!("{}", render::ascii_wire(pcb, from, path.clone(), pcb.entity_rect().pad(1)));
@@ -200,24 +209,24 @@ fn apply_lee_path<I: IntoIterator<Item = LogisticRoute>>(pcb: &mut impl Pcb, fro
         match belt {
             LogisticRoute::Normal(dir) => {
                 let function = match kind {
-                    WireKind::Belt => Function::Belt(dir),
+                    WireKind::Belt => Function::Belt(dir, tier),
                     WireKind::Pipe(ref x) => Function::Pipe(x.clone()),
                 };
-                add_beginning(Entity { location: cursor, function });
+                add_beginning(Entity { location: cursor, function })?;
             },
             LogisticRoute::Underground { dir, .. } => {
                 let (f1, f2) = match kind {
-                    WireKind::Belt => (Function::UndergroundBelt(dir, true), Function::UndergroundBelt(dir, false)),
+                    WireKind::Belt => (Function::UndergroundBelt(dir, true, tier), Function::UndergroundBelt(dir, false, tier)),
                     WireKind::Pipe(_) => (Function::UndergroundPipe(dir.opposite_direction()), Function::UndergroundPipe(dir)),
                 };
                 add_beginning(Entity {
                     location: cursor,
                     function: f1,
-                });
+                })?;
                 pcb.add(Entity {
                     location: belt.underground_belt_end_position(cursor).unwrap(),
                     function: f2,
-                });
+                })?;
             },
         }
         cursor = belt.position_after(cursor);
@@ -18,14 +18,15 @@ bitflags::bitflags! {
 
 
 #[throws(())]
-pub fn mylee(pcb: &mut impl Pcb, &NeededWire { from, to, ref wire_kind }: &NeededWire, opts: Options) {
+pub fn mylee(pcb: &mut impl Pcb, &NeededWire { from, to, ref wire_kind, belt }: &NeededWire, opts: Options) {
+    let gap_size = wire_kind.gap_size(belt);
     let path = if opts.contains(Options::VISITED_WITH_DIRECTIONS) {
-        mylee_internal::<_, WithDirections>(pcb, &ALL_DIRECTIONS, from, to, opts, wire_kind)
+        mylee_internal::<_, WithDirections>(pcb, &ALL_DIRECTIONS, from, to, opts, wire_kind, gap_size)
     } else {
-        mylee_internal::<_, WithoutDirections>(pcb, &ALL_DIRECTIONS, from, to, opts, wire_kind)
+        mylee_internal::<_, WithoutDirections>(pcb, &ALL_DIRECTIONS, from, to, opts, wire_kind, gap_size)
     };
 
-    apply_lee_path(pcb, from, path.ok_or(())?, wire_kind);
+    apply_lee_path(pcb, from, path.ok_or(())?, wire_kind, belt).map_err(|_| ())?;
 }
 
 struct MazewalkerHistoryEntry<'a> {
@@ -165,7 +166,7 @@ impl<G: VisitedArray> Visited<G> {
 
 #[inline(never)]
 fn mylee_internal<P: Pcb, G: VisitedArray>(
-    pcb: &P, moveset: &[Direction], from: Point, to: Point, opts: Options, kind: &WireKind,
+    pcb: &P, moveset: &[Direction], from: Point, to: Point, opts: Options, kind: &WireKind, gap_size: usize,
 ) -> Option<Vec<LogisticRoute>> {
     // ensure enough space around possible entities to possibly lay a belt around everything,
     // including a possible underground belt out, followed by an underground belt back in
@@ -208,7 +209,7 @@ fn mylee_internal<P: Pcb, G: VisitedArray>(
                         path = insert_underground_belts(path.into_iter().map(|b| match b {
                             LogisticRoute::Normal(d) => d,
                             _ => unreachable!(),
-                        }), kind.gap_size());
+                        }), gap_size);
                     }
                     return Some(path);
                 }
@@ -244,11 +245,11 @@ fn mylee_internal<P: Pcb, G: VisitedArray>(
                     Some(belt) => belt.direction(),
                     None => continue,
                 };
-                for gap in 0..=(kind.gap_size() as i32) {
+                for gap in 0..=(gap_size as i32) {
                     let underground_end = walker.pos + (dir.to_vector() * (gap + 1));
                     // check for no interference with other underground belts in the way
                     match pcb.entity_at(underground_end) {
-                        Some(Entity { function: Function::UndergroundBelt(intersecting_dir, _), .. })
+                        Some(Entity { function: Function::UndergroundBelt(intersecting_dir, ..), .. })
                             if intersecting_dir.is_same_axis(dir) && kind == &WireKind::Belt => break,
                         Some(Entity { function: Function::UndergroundPipe(intersecting_dir), .. })
                             if intersecting_dir.is_same_axis(dir) && kind != &WireKind::Belt => break,